@@ -0,0 +1,114 @@
+//! Times translating a large generated program: as the translator did before it streamed,
+//! kept in a `String`, and streamed straight into a sink. Run with `cargo bench`.
+
+use stack_vm::codewriter::CodeWriter;
+use stack_vm::hack;
+use stack_vm::ir::Module;
+use stack_vm::optimizer;
+use stack_vm::parser::Parser;
+use stack_vm::translator;
+use stack_vm::verifier;
+use std::hint::black_box;
+use std::io;
+use std::time::{Duration, Instant};
+
+const FUNCTIONS: usize = 2000;
+const ITERATIONS: u32 = 10;
+
+/// A program exercising every kind of command, `FUNCTIONS` functions long
+fn program() -> Vec<(String, String)> {
+    let mut source = String::from("function Sys.init 0\ncall Main.f0 0\nlabel END\ngoto END\n");
+    for i in 0..FUNCTIONS {
+        source.push_str(&format!(
+            "function Main.f{i} 2\n\
+             push argument 0\npush constant 17\nadd\npop local 0\n\
+             label LOOP\n\
+             push local 0\npush constant 1\nsub\npop local 0\n\
+             push local 0\npush constant 0\ngt\nif-goto LOOP\n\
+             push static {s}\npush pointer 0\npush that 3\nand\nnot\npop this 2\n\
+             push local 1\npush temp 4\nlt\nneg\npop static {s}\n\
+             call Main.f{next} 0\nreturn\n",
+            s = i % 200,
+            next = (i + 1) % FUNCTIONS
+        ));
+    }
+    vec![("Main.vm".to_string(), source)]
+}
+
+fn time(name: &str, mut run: impl FnMut() -> usize) {
+    let mut best = Duration::MAX;
+    let mut bytes = 0;
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        bytes = black_box(run());
+        best = best.min(start.elapsed());
+    }
+    println!(
+        "{:<20} {:>8.2} ms  {:>7.1} MB/s",
+        name,
+        best.as_secs_f64() * 1000.0,
+        bytes as f64 / best.as_secs_f64() / 1e6
+    );
+}
+
+/// Counts what it is given and drops it, like writing to `/dev/null`
+struct Sink(usize);
+
+impl io::Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The generated program is larger than the Hack ROM, which only matters on the real machine
+fn codewriter() -> CodeWriter {
+    let mut codewriter = CodeWriter::new();
+    codewriter.set_rom_budget(usize::MAX);
+    codewriter
+}
+
+/// The translation before streaming: each block printed to a `String` of its own and
+/// appended to the output, which is verified again once complete. It builds no source map,
+/// so it is, if anything, timed too kindly.
+fn per_block_strings(inputs: &[(String, String)]) -> usize {
+    let mut codewriter = codewriter();
+    let mut result = hack::print(&codewriter.write_bootstrap());
+    result.push('\n');
+    for (filename, content) in inputs {
+        codewriter.set_file_context(filename.trim_end_matches(".vm").to_string());
+        let commands = Parser::new(content).collect::<Result<Vec<_>, _>>().unwrap();
+        let commands =
+            optimizer::optimize(Module::from_commands(commands).unwrap().into_commands());
+
+        let mut position = 0;
+        while position < commands.len() {
+            let (consumed, assembly) = codewriter.write_next(&commands[position..]);
+            result.push_str(&hack::print(&assembly));
+            result.push('\n');
+            position += consumed;
+        }
+    }
+    verifier::verify(&result).unwrap();
+    result.len()
+}
+
+fn main() {
+    let inputs = program();
+
+    time("per-block Strings", || per_block_strings(&inputs));
+
+    time("into String", || {
+        let (result, _) = translator::translate_with(codewriter(), inputs.clone(), true).unwrap();
+        result.len()
+    });
+    time("streamed to sink", || {
+        let mut sink = Sink(0);
+        translator::translate_to_writer(codewriter(), inputs.clone(), true, &mut sink).unwrap();
+        sink.0
+    });
+}
@@ -0,0 +1,273 @@
+use super::{function_name, identifier, Native, NativeTarget, Program};
+use crate::command::{Command, MemorySegment};
+
+/// Lowers VM programs to a single C file that keeps the Hack RAM layout and 16-bit words.
+///
+/// Each VM function becomes a C function, and its labels C labels. The conventional halt sets
+/// a flag and unwinds instead of spinning. The compiled program takes `address=value`
+/// arguments to set RAM before running and prints `RAM[address]` for each bare `address`
+/// argument afterwards.
+pub type CBackend = Native<C>;
+
+#[derive(Debug, Default)]
+pub struct C;
+
+const INCLUDES: &str = "\
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+static uint16_t RAM[32768];
+static int halted;
+";
+
+const HELPERS: &str = "\
+/* reads a word as two's complement */
+static int word(unsigned value) {
+    value &= 0xFFFFu;
+    return value < 0x8000u ? (int)value : (int)value - 0x10000;
+}
+
+static void push(int value) {
+    M(SP) = (uint16_t)value;
+    SP++;
+}
+
+static uint16_t pop(void) {
+    SP--;
+    return M(SP);
+}
+";
+
+impl NativeTarget for C {
+    const COMMENT: &'static str = "    //";
+
+    fn prologue(&self, program: &Program) -> String {
+        let pointer = program.memory_map().pointer;
+        format!(
+            "{}\n\
+             #define M(address) RAM[(address) & 0x7FFF]\n\
+             #define SP RAM[0]\n\
+             #define LCL RAM[1]\n\
+             #define ARG RAM[2]\n\
+             #define THIS RAM[{}]\n\
+             #define THAT RAM[{}]\n\n\
+             {}",
+            INCLUDES,
+            pointer,
+            pointer + 1,
+            HELPERS
+        )
+    }
+
+    fn start_routine(&mut self, name: &str, _public: bool) -> String {
+        format!("void {}(void) {{\n", name)
+    }
+
+    fn end_routine(&self) -> &'static str {
+        "}\n\n"
+    }
+
+    fn write(&mut self, command: &Command, program: &mut Program) -> String {
+        match command {
+            Command::Placeholder => String::new(),
+            Command::Add => binary_op("x + y"),
+            Command::Sub => binary_op("x - y"),
+            Command::And => binary_op("x & y"),
+            Command::Or => binary_op("x | y"),
+            Command::Eq => binary_op("x == y ? -1 : 0"),
+            Command::Gt => binary_op("word(x - y) > 0 ? -1 : 0"),
+            Command::Lt => binary_op("word(x - y) < 0 ? -1 : 0"),
+            Command::Neg => "    push(-pop());".to_string(),
+            Command::Not => "    push(~pop());".to_string(),
+            Command::Push(MemorySegment::Constant, value) => format!("    push({});", value),
+            Command::Push(segment, index) => {
+                format!("    push({});", segment_address(program, segment, *index))
+            }
+            Command::Pop(segment, index) => {
+                format!("    {} = pop();", segment_address(program, segment, *index))
+            }
+            Command::Label(label) => format!("L_{}:;", identifier(label)),
+            Command::Goto(label) => format!("    goto L_{};", identifier(label)),
+            Command::IfGoto(label) => format!("    if (pop() != 0) goto L_{};", identifier(label)),
+            Command::Function(_, nlocals) if *nlocals > 0 => {
+                format!("    for (int i = 0; i < {}; i++) push(0);", nlocals)
+            }
+            Command::Function(_, _) => String::new(),
+            Command::Call(name, nargs) => [
+                format!(
+                    "    push({}); push(LCL); push(ARG); push(THIS); push(THAT);",
+                    program.call_site(name)
+                ),
+                format!("    ARG = SP - 5 - {};", nargs),
+                "    LCL = SP;".to_string(),
+                format!("    {{ void {0}(void); {0}(); }}", function_name(name)),
+                "    if (halted) return;".to_string(),
+            ]
+            .join("\n"),
+            Command::Return => [
+                "    {",
+                "        uint16_t frame = LCL;",
+                "        M(ARG) = pop();",
+                "        SP = ARG + 1;",
+                "        THAT = M(frame - 1);",
+                "        THIS = M(frame - 2);",
+                "        ARG = M(frame - 3);",
+                "        LCL = M(frame - 4);",
+                "        return;",
+                "    }",
+            ]
+            .join("\n"),
+        }
+    }
+
+    fn write_halt(&mut self, label: &str, _program: &mut Program) -> String {
+        format!("L_{}:\n    halted = 1;\n    return;", identifier(label))
+    }
+
+    fn write_set(&self, address: u16, value: u16) -> String {
+        format!("    M({}) = {};", address, value)
+    }
+
+    /// `main` sets RAM from the arguments, runs the entries, and prints the RAM asked for
+    fn epilogue(&self, entries: &[String], _program: &Program) -> String {
+        let entries: Vec<String> = entries
+            .iter()
+            .map(|name| format!("    {}();\n", name))
+            .collect();
+        format!(
+            "int main(int argc, char **argv) {{\n\
+             \x20   for (int i = 1; i < argc; i++) {{\n\
+             \x20       char *value = strchr(argv[i], '=');\n\
+             \x20       if (value) M(atoi(argv[i])) = (uint16_t)atoi(value + 1);\n\
+             \x20   }}\n\
+             {}\
+             \x20   for (int i = 1; i < argc; i++) {{\n\
+             \x20       if (!strchr(argv[i], '=')) printf(\"%d\\n\", word(M(atoi(argv[i]))));\n\
+             \x20   }}\n\
+             \x20   return 0;\n\
+             }}",
+            entries.concat()
+        )
+    }
+}
+
+/// The memory cell `segment index` refers to, as a C lvalue
+fn segment_address(program: &mut Program, segment: &MemorySegment, index: u16) -> String {
+    match segment {
+        MemorySegment::Local => format!("M(LCL + {})", index),
+        MemorySegment::Argument => format!("M(ARG + {})", index),
+        MemorySegment::This => format!("M(THIS + {})", index),
+        MemorySegment::That => format!("M(THAT + {})", index),
+        MemorySegment::Constant => panic!("constant segment has no address"),
+        fixed => format!("M({})", program.address(fixed, index).unwrap()),
+    }
+}
+
+fn binary_op(result: &str) -> String {
+    format!("    {{ uint16_t y = pop(), x = pop(); push({}); }}", result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Backend;
+    use crate::codewriter::BootstrapConfig;
+    use crate::hack::MemoryMap;
+
+    fn write_next(backend: &mut CBackend, commands: &[Command]) -> (usize, String) {
+        let mut code = String::new();
+        let consumed = backend.write_next(commands, &mut code).unwrap();
+        (consumed, code)
+    }
+
+    #[test]
+    fn test_statics_allocated_in_order_of_use() {
+        let mut backend = CBackend::new();
+        backend.set_file_context("Main".to_string());
+        backend.write(&Command::Push(MemorySegment::Static, 3));
+        backend.set_file_context("Util".to_string());
+
+        assert_eq!(
+            backend.write(&Command::Pop(MemorySegment::Static, 0)),
+            "    M(17) = pop();"
+        );
+    }
+
+    #[test]
+    fn test_self_loop_halts() {
+        let mut backend = CBackend::new();
+        backend.set_file_context("Sys".to_string());
+        let commands = [
+            Command::Function("Sys.init".to_string(), 0),
+            Command::Label("END".to_string()),
+            Command::Goto("END".to_string()),
+        ];
+
+        assert_eq!(write_next(&mut backend, &commands).0, 1);
+        let (consumed, code) = write_next(&mut backend, &commands[1..]);
+        assert_eq!(consumed, 2);
+        assert!(code.ends_with("L_END:\n    halted = 1;\n    return;\n"));
+    }
+
+    #[test]
+    fn test_memory_map_honored() {
+        let mut backend = CBackend::new();
+        backend
+            .set_memory_map(MemoryMap {
+                temp: 20,
+                pointer: 10,
+                statics: 100..=100,
+                ..Default::default()
+            })
+            .unwrap();
+        backend.set_file_context("Main".to_string());
+
+        let mut prologue = String::new();
+        backend.write_prologue(&mut prologue).unwrap();
+        assert!(prologue.contains("#define THIS RAM[10]\n#define THAT RAM[11]\n"));
+        assert_eq!(
+            backend.write(&Command::Pop(MemorySegment::Temp, 1)),
+            "    M(21) = pop();"
+        );
+        assert_eq!(
+            backend.write(&Command::Pop(MemorySegment::Pointer, 1)),
+            "    M(11) = pop();"
+        );
+        assert_eq!(
+            backend.write(&Command::Pop(MemorySegment::Static, 0)),
+            "    M(100) = pop();"
+        );
+        assert!(backend.check().is_ok());
+
+        backend.write(&Command::Pop(MemorySegment::Static, 1));
+        assert_eq!(
+            backend.check().unwrap_err(),
+            "Too many static variables: 2 variables, 1 addresses"
+        );
+    }
+
+    #[test]
+    fn test_bootstrap_config_honored() {
+        let mut backend = CBackend::new();
+        backend
+            .set_memory_map(MemoryMap {
+                stack: 300..=1000,
+                ..Default::default()
+            })
+            .unwrap();
+        backend.set_bootstrap(BootstrapConfig {
+            entry: "Main.main".to_string(),
+            that: Some(4000),
+            ..Default::default()
+        });
+
+        let mut bootstrap = String::new();
+        backend.write_bootstrap(&mut bootstrap).unwrap();
+        assert!(bootstrap
+            .starts_with("void run_bootstrap(void) {\n    M(0) = 300;\n    M(4) = 4000;\n"));
+        assert!(bootstrap.contains("vm_Main_dmain();"));
+        assert_eq!(backend.entry_point(), "Main.main");
+    }
+}
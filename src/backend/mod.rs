@@ -0,0 +1,387 @@
+use crate::codewriter::BootstrapConfig;
+use crate::command::{Command, MemorySegment};
+use crate::hack::MemoryMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+pub mod c;
+pub mod rust;
+pub mod x86;
+
+/// A target the translator can lower VM commands to. Each `write_*` method writes whole
+/// lines straight into the output.
+pub trait Backend {
+    /// Declarations the rest of the output relies on
+    fn write_prologue(&mut self, _out: &mut dyn fmt::Write) -> fmt::Result {
+        Ok(())
+    }
+
+    /// Code that sets up the machine and calls `entry_point`
+    fn write_bootstrap(&mut self, out: &mut dyn fmt::Write) -> fmt::Result;
+
+    /// The function the bootstrap calls
+    fn entry_point(&self) -> &str {
+        "Sys.init"
+    }
+
+    /// The functions the whole program defines, known before any of it is written
+    fn set_defined_functions(&mut self, _functions: &HashSet<String>) {}
+
+    /// Switches to the module whose commands are written next, e.g. to name its statics
+    fn set_file_context(&mut self, module: String);
+
+    /// Records where the next command(s) came from
+    fn set_source_context(&mut self, _source: &str, _line: usize) {}
+
+    /// Writes the command(s) at the front of `commands`, returning how many were consumed
+    fn write_next(
+        &mut self,
+        commands: &[Command],
+        out: &mut dyn fmt::Write,
+    ) -> Result<usize, fmt::Error>;
+
+    /// Checks the complete program once every command has been written
+    fn check(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Code that follows the rest of the program
+    fn write_epilogue(&mut self, _out: &mut dyn fmt::Write) -> fmt::Result {
+        Ok(())
+    }
+}
+
+/// Lets a caller hold on to a backend, e.g. to read what it learned about the program
+impl<B: Backend + ?Sized> Backend for &mut B {
+    fn write_prologue(&mut self, out: &mut dyn fmt::Write) -> fmt::Result {
+        (**self).write_prologue(out)
+    }
+
+    fn write_bootstrap(&mut self, out: &mut dyn fmt::Write) -> fmt::Result {
+        (**self).write_bootstrap(out)
+    }
+
+    fn entry_point(&self) -> &str {
+        (**self).entry_point()
+    }
+
+    fn set_defined_functions(&mut self, functions: &HashSet<String>) {
+        (**self).set_defined_functions(functions)
+    }
+
+    fn set_file_context(&mut self, module: String) {
+        (**self).set_file_context(module)
+    }
+
+    fn set_source_context(&mut self, source: &str, line: usize) {
+        (**self).set_source_context(source, line)
+    }
+
+    fn write_next(
+        &mut self,
+        commands: &[Command],
+        out: &mut dyn fmt::Write,
+    ) -> Result<usize, fmt::Error> {
+        (**self).write_next(commands, out)
+    }
+
+    fn write_epilogue(&mut self, out: &mut dyn fmt::Write) -> fmt::Result {
+        (**self).write_epilogue(out)
+    }
+
+    fn check(&mut self) -> Result<(), String> {
+        (**self).check()
+    }
+}
+
+/// The code a native target writes for each part of a program. `Native` decides which parts
+/// go where and keeps what they share, so a target only lowers commands; like the Hack code,
+/// its comparisons go by the sign of the wrapped difference.
+pub trait NativeTarget: Default {
+    /// What a line of the commands' source starts with, indented to the routine's statements
+    const COMMENT: &'static str;
+
+    /// Declarations the rest of the output relies on
+    fn prologue(&self, program: &Program) -> String;
+
+    /// Starts a routine, `public` for the VM's functions
+    fn start_routine(&mut self, name: &str, public: bool) -> String;
+
+    /// Ends the routine started last
+    fn end_routine(&self) -> &'static str;
+
+    fn write(&mut self, command: &Command, program: &mut Program) -> String;
+
+    /// Stops the whole program at a `label L` directly followed by `goto L`, which would
+    /// otherwise spin
+    fn write_halt(&mut self, label: &str, program: &mut Program) -> String;
+
+    /// Sets a RAM word, for the bootstrap
+    fn write_set(&self, address: u16, value: u16) -> String;
+
+    /// Follows the last routine with the program's entry point, which runs `entries` in turn
+    fn epilogue(&self, entries: &[String], program: &Program) -> String;
+}
+
+/// What the native targets track about the program being written
+#[derive(Debug, Default)]
+pub struct Program {
+    memory_map: MemoryMap,
+    bootstrap: BootstrapConfig,
+    module: String,
+    statics: HashMap<String, u16>,
+    routine: Option<String>,
+    new_file: bool,
+    entries: Vec<String>,
+    first_function: Option<String>,
+    defined: HashSet<String>,
+    called: Vec<String>,
+    call_sites: usize,
+}
+
+impl Program {
+    pub fn memory_map(&self) -> &MemoryMap {
+        &self.memory_map
+    }
+
+    /// The routine being written
+    pub fn routine(&self) -> &str {
+        self.routine.as_deref().unwrap_or_default()
+    }
+
+    /// The fixed address `segment index` refers to, or `None` for the segments reached
+    /// through LCL, ARG, THIS or THAT. Statics are allocated in order of first use, as the
+    /// Hack assembler allocates variables, so native targets keep the same memory layout.
+    pub fn address(&mut self, segment: &MemorySegment, index: u16) -> Option<u16> {
+        match segment {
+            MemorySegment::Pointer => Some(self.memory_map.pointer + index),
+            MemorySegment::Temp => Some(self.memory_map.temp + index),
+            MemorySegment::Static => {
+                let next = self
+                    .memory_map
+                    .statics
+                    .start()
+                    .saturating_add(self.statics.len() as u16);
+                Some(
+                    *self
+                        .statics
+                        .entry(format!("{}.{}", self.module, index))
+                        .or_insert(next),
+                )
+            }
+            _ => None,
+        }
+    }
+
+    /// Records a call to `name`. The return address only needs to be distinct, so each call
+    /// site gets its own number.
+    pub fn call_site(&mut self, name: &str) -> usize {
+        self.call_sites += 1;
+        self.called.push(name.to_string());
+        self.call_sites
+    }
+}
+
+/// A backend for a native target: the VM's functions become routines that call each other
+/// directly, any top-level code runs in routines of its own, and the Hack RAM layout is
+/// kept, laid out by the memory map
+#[derive(Debug, Default)]
+pub struct Native<T> {
+    target: T,
+    program: Program,
+}
+
+impl<T: NativeTarget> Native<T> {
+    pub fn new() -> Self {
+        Native::default()
+    }
+
+    /// Lays out the segments as for the Hack target; the heap, traps and counters serve
+    /// checks and profiling, which native targets don't do
+    pub fn set_memory_map(&mut self, memory_map: MemoryMap) -> Result<(), String> {
+        memory_map.validate()?;
+        self.program.memory_map = memory_map;
+        Ok(())
+    }
+
+    /// Sets what the bootstrap calls and the pointers it starts with. The program always
+    /// stops once the entry function returns.
+    pub fn set_bootstrap(&mut self, config: BootstrapConfig) {
+        self.program.bootstrap = config;
+    }
+
+    pub fn write(&mut self, command: &Command) -> String {
+        self.target.write(command, &mut self.program)
+    }
+
+    /// Ends the routine being written, if any, and starts another
+    fn open(&mut self, name: String, public: bool) -> String {
+        let end = match self.program.routine {
+            Some(_) => self.target.end_routine(),
+            None => "",
+        };
+        let start = self.target.start_routine(&name, public);
+        self.program.routine = Some(name);
+        format!("{}{}", end, start)
+    }
+}
+
+impl<T: NativeTarget> Backend for Native<T> {
+    fn write_prologue(&mut self, out: &mut dyn fmt::Write) -> fmt::Result {
+        writeln!(out, "{}", self.target.prologue(&self.program))
+    }
+
+    fn write_bootstrap(&mut self, out: &mut dyn fmt::Write) -> fmt::Result {
+        self.program.entries.push("run_bootstrap".to_string());
+        out.write_str(&self.open("run_bootstrap".to_string(), false))?;
+
+        let config = self.program.bootstrap.clone();
+        let pointer = self.program.memory_map.pointer;
+        let pointers = [
+            (
+                0,
+                Some(config.sp.unwrap_or(*self.program.memory_map.stack.start())),
+            ),
+            (1, config.lcl),
+            (2, config.arg),
+            (pointer, config.this),
+            (pointer + 1, config.that),
+        ];
+        for (address, value) in pointers {
+            if let Some(value) = value {
+                writeln!(out, "{}", self.target.write_set(address, value))?;
+            }
+        }
+        writeln!(out, "{}", self.write(&Command::Call(config.entry, 0)))
+    }
+
+    fn entry_point(&self) -> &str {
+        &self.program.bootstrap.entry
+    }
+
+    fn set_file_context(&mut self, module: String) {
+        self.program.module = module;
+        self.program.new_file = true;
+    }
+
+    fn write_next(
+        &mut self,
+        commands: &[Command],
+        out: &mut dyn fmt::Write,
+    ) -> Result<usize, fmt::Error> {
+        match commands.first() {
+            Some(Command::Function(name, _)) => {
+                self.program.defined.insert(name.clone());
+                self.program.first_function.get_or_insert(name.clone());
+                out.write_str(&self.open(function_name(name), true))?;
+            }
+            Some(_) if self.program.new_file || self.program.routine.is_none() => {
+                let name = format!("run_top_{}", self.program.entries.len() + 1);
+                self.program.entries.push(name.clone());
+                out.write_str(&self.open(name, false))?;
+            }
+            Some(_) => {}
+            None => return Ok(0),
+        }
+        self.program.new_file = false;
+
+        let (consumed, body) = match commands {
+            [Command::Label(label), Command::Goto(target), ..] if label == target => {
+                (2, self.target.write_halt(label, &mut self.program))
+            }
+            [command, ..] => (1, self.write(command)),
+            [] => unreachable!(),
+        };
+        for command in &commands[..consumed] {
+            writeln!(out, "{} {}", T::COMMENT, command)?;
+        }
+        writeln!(out, "{}", body)?;
+        Ok(consumed)
+    }
+
+    fn check(&mut self) -> Result<(), String> {
+        check_calls(&self.program.called, &self.program.defined)?;
+        let room = self.program.memory_map.statics.len();
+        if self.program.statics.len() > room {
+            return Err(format!(
+                "Too many static variables: {} variables, {} addresses",
+                self.program.statics.len(),
+                room
+            ));
+        }
+        Ok(())
+    }
+
+    /// Ends the last routine and adds the entry point, which runs the bootstrap, or else any
+    /// top-level code, or else the first function
+    fn write_epilogue(&mut self, out: &mut dyn fmt::Write) -> fmt::Result {
+        let end = match self.program.routine {
+            Some(_) => self.target.end_routine(),
+            None => "",
+        };
+        let entries = entry_points(&self.program.entries, &self.program.first_function);
+        writeln!(
+            out,
+            "{}{}",
+            end,
+            self.target.epilogue(&entries, &self.program)
+        )
+    }
+}
+
+/// Native targets link VM functions directly, so a call to a missing one is caught up front
+fn check_calls(called: &[String], defined: &HashSet<String>) -> Result<(), String> {
+    let undefined: Vec<String> = called
+        .iter()
+        .filter(|name| !defined.contains(*name))
+        .map(|name| format!("call to undefined function: {}", name))
+        .collect();
+    if undefined.is_empty() {
+        Ok(())
+    } else {
+        Err(undefined.join("\n"))
+    }
+}
+
+/// What a native program runs: the bootstrap if there is one, or else the files' top-level
+/// code in order, or else the first function
+fn entry_points(entries: &[String], first_function: &Option<String>) -> Vec<String> {
+    match (entries.first(), first_function) {
+        (Some(bootstrap), _) if bootstrap == "run_bootstrap" => vec![bootstrap.clone()],
+        (Some(_), _) => entries.to_vec(),
+        (None, Some(name)) => vec![function_name(name)],
+        (None, None) => Vec::new(),
+    }
+}
+
+fn function_name(name: &str) -> String {
+    format!("vm_{}", identifier(name))
+}
+
+/// Escapes a VM identifier into one C and assemblers accept, keeping distinct names distinct
+fn identifier(name: &str) -> String {
+    let mut escaped = String::new();
+    for c in name.chars() {
+        match c {
+            c if c.is_ascii_alphanumeric() => escaped.push(c),
+            '_' => escaped.push_str("_u"),
+            '.' => escaped.push_str("_d"),
+            '$' => escaped.push_str("_s"),
+            ':' => escaped.push_str("_c"),
+            c => escaped.push_str(&format!("_x{:x}_", c as u32)),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identifiers_stay_distinct() {
+        assert_eq!(identifier("Main.main"), "Main_dmain");
+        assert_ne!(identifier("a_b"), identifier("a.b"));
+        assert_ne!(identifier("a_db"), identifier("a.b"));
+    }
+}
@@ -0,0 +1,224 @@
+use super::{function_name, Native, NativeTarget, Program};
+use crate::command::{Command, MemorySegment};
+use std::collections::HashMap;
+
+/// Lowers VM programs to Rust items that can be `include!`d or used as a module.
+///
+/// `run(ram)` runs the program on a Hack RAM, and each VM function `F` is a
+/// `pub fn vm_F(ram) -> bool` that returns whether the program halted. Rust has no goto, so
+/// each function is a loop over a `match` with one arm per label.
+pub type RustBackend = Native<Rust>;
+
+#[derive(Debug, Default)]
+pub struct Rust {
+    blocks: HashMap<String, usize>,
+}
+
+const PROLOGUE: &str = "\
+fn vm_at(address: i32) -> usize {
+    (address & 0x7FFF) as usize
+}
+
+fn vm_push(ram: &mut [i16; 32768], value: i16) {
+    ram[vm_at(ram[0] as i32)] = value;
+    ram[0] = ram[0].wrapping_add(1);
+}
+
+fn vm_pop(ram: &mut [i16; 32768]) -> i16 {
+    ram[0] = ram[0].wrapping_sub(1);
+    ram[vm_at(ram[0] as i32)]
+}
+";
+
+const ROUTINE_END: &str = "                return false;
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+";
+
+impl NativeTarget for Rust {
+    const COMMENT: &'static str = "                //";
+
+    fn prologue(&self, _program: &Program) -> String {
+        PROLOGUE.to_string()
+    }
+
+    fn start_routine(&mut self, name: &str, public: bool) -> String {
+        self.blocks.clear();
+        format!(
+            "#[allow(non_snake_case, unused_mut, unreachable_code)]\n\
+             {}fn {}(ram: &mut [i16; 32768]) -> bool {{\n\
+             \x20   let mut block = 0;\n\
+             \x20   loop {{\n\
+             \x20       match block {{\n\
+             \x20           0 => {{\n",
+            if public { "pub " } else { "" },
+            name
+        )
+    }
+
+    fn end_routine(&self) -> &'static str {
+        ROUTINE_END
+    }
+
+    fn write(&mut self, command: &Command, program: &mut Program) -> String {
+        let pointer = program.memory_map().pointer;
+        let statements = match command {
+            Command::Placeholder => String::new(),
+            Command::Add => binary_op("x.wrapping_add(y)"),
+            Command::Sub => binary_op("x.wrapping_sub(y)"),
+            Command::And => binary_op("x & y"),
+            Command::Or => binary_op("x | y"),
+            Command::Eq => binary_op("-((x == y) as i16)"),
+            Command::Gt => binary_op("-((x.wrapping_sub(y) > 0) as i16)"),
+            Command::Lt => binary_op("-((x.wrapping_sub(y) < 0) as i16)"),
+            Command::Neg => "let x = vm_pop(ram); vm_push(ram, x.wrapping_neg());".to_string(),
+            Command::Not => "let x = vm_pop(ram); vm_push(ram, !x);".to_string(),
+            Command::Push(MemorySegment::Constant, value) => {
+                format!("vm_push(ram, {});", value)
+            }
+            Command::Push(segment, index) => format!(
+                "let x = ram[{}]; vm_push(ram, x);",
+                segment_index(program, segment, *index)
+            ),
+            Command::Pop(segment, index) => format!(
+                "let x = vm_pop(ram); ram[{}] = x;",
+                segment_index(program, segment, *index)
+            ),
+            Command::Label(label) => return self.write_label(label),
+            Command::Goto(label) => format!("block = {}; continue;", self.block(label)),
+            Command::IfGoto(label) => format!(
+                "if vm_pop(ram) != 0 {{ block = {}; continue; }}",
+                self.block(label)
+            ),
+            Command::Function(_, nlocals) if *nlocals > 0 => {
+                format!("for _ in 0..{} {{ vm_push(ram, 0); }}", nlocals)
+            }
+            Command::Function(_, _) => String::new(),
+            Command::Call(name, nargs) => [
+                format!("vm_push(ram, {});", program.call_site(name)),
+                format!(
+                    "for pointer in [1, 2, {}, {}] {{ let x = ram[pointer]; vm_push(ram, x); }}",
+                    pointer,
+                    pointer + 1
+                ),
+                format!("ram[2] = ram[0].wrapping_sub({});", 5 + *nargs as u32),
+                "ram[1] = ram[0];".to_string(),
+                format!("if {}(ram) {{ return true; }}", function_name(name)),
+            ]
+            .join("\n"),
+            Command::Return => [
+                "let frame = ram[1] as i32;".to_string(),
+                "let x = vm_pop(ram);".to_string(),
+                "ram[vm_at(ram[2] as i32)] = x;".to_string(),
+                "ram[0] = ram[2].wrapping_add(1);".to_string(),
+                format!("ram[{}] = ram[vm_at(frame - 1)];", pointer + 1),
+                format!("ram[{}] = ram[vm_at(frame - 2)];", pointer),
+                "ram[2] = ram[vm_at(frame - 3)];".to_string(),
+                "ram[1] = ram[vm_at(frame - 4)];".to_string(),
+                "return false;".to_string(),
+            ]
+            .join("\n"),
+        };
+        indent(&statements)
+    }
+
+    fn write_halt(&mut self, label: &str, _program: &mut Program) -> String {
+        format!("{}\n{}", self.write_label(label), indent("return true;"))
+    }
+
+    fn write_set(&self, address: u16, value: u16) -> String {
+        indent(&format!("ram[{}] = {};", address, value as i16))
+    }
+
+    /// `run` runs the entries until one halts
+    fn epilogue(&self, entries: &[String], _program: &Program) -> String {
+        let entries: Vec<String> = entries
+            .iter()
+            .map(|name| format!("    if {}(ram) {{\n        return;\n    }}\n", name))
+            .collect();
+        format!(
+            "/// Runs the program until it halts or runs out of code\n\
+             pub fn run(ram: &mut [i16; 32768]) {{\n\
+             {}\
+             }}",
+            entries.concat()
+        )
+    }
+}
+
+impl Rust {
+    /// Ends the current arm, falling through to the label's
+    fn write_label(&mut self, label: &str) -> String {
+        let block = self.block(label);
+        format!(
+            "                block = {0};\n            }}\n            {0} => {{",
+            block
+        )
+    }
+
+    /// The match arm a label starts, numbered in order of first mention
+    fn block(&mut self, label: &str) -> usize {
+        let next = self.blocks.len() + 1;
+        *self.blocks.entry(label.to_string()).or_insert(next)
+    }
+}
+
+fn binary_op(result: &str) -> String {
+    format!(
+        "let y = vm_pop(ram);\nlet x = vm_pop(ram);\nvm_push(ram, {});",
+        result
+    )
+}
+
+/// The RAM index `segment index` refers to
+fn segment_index(program: &mut Program, segment: &MemorySegment, index: u16) -> String {
+    let pointer = program.memory_map().pointer;
+    match segment {
+        MemorySegment::Local => format!("vm_at(ram[1] as i32 + {})", index),
+        MemorySegment::Argument => format!("vm_at(ram[2] as i32 + {})", index),
+        MemorySegment::This => format!("vm_at(ram[{}] as i32 + {})", pointer, index),
+        MemorySegment::That => format!("vm_at(ram[{}] as i32 + {})", pointer + 1, index),
+        MemorySegment::Constant => panic!("constant segment has no address"),
+        fixed => program.address(fixed, index).unwrap().to_string(),
+    }
+}
+
+/// Indents statements to the depth of a match arm's body
+fn indent(statements: &str) -> String {
+    statements
+        .lines()
+        .map(|line| format!("                {}", line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Backend;
+
+    fn write_next(backend: &mut RustBackend, commands: &[Command]) -> (usize, String) {
+        let mut code = String::new();
+        let consumed = backend.write_next(commands, &mut code).unwrap();
+        (consumed, code)
+    }
+
+    #[test]
+    fn test_labels_start_match_arms() {
+        let mut backend = RustBackend::new();
+        backend.set_file_context("Main".to_string());
+        write_next(
+            &mut backend,
+            &[Command::Function("Main.loop".to_string(), 0)],
+        );
+
+        let (_, code) = write_next(&mut backend, &[Command::Goto("END".to_string())]);
+        assert!(code.ends_with("block = 1; continue;\n"));
+        let (_, code) = write_next(&mut backend, &[Command::Label("END".to_string())]);
+        assert!(code.ends_with("block = 1;\n            }\n            1 => {\n"));
+    }
+}
@@ -0,0 +1,300 @@
+use super::{function_name, identifier, Native, NativeTarget, Program};
+use crate::command::{Command, MemorySegment};
+
+/// Lowers VM programs to x86-64 GNU assembly (AT&T syntax) for a static Linux executable.
+///
+/// The Hack RAM is a `.bss` array of words, VM calls are native calls, and the process exits
+/// with the first word of the stack as its status once the program halts or runs out of code.
+/// Like the C target, `address=value` arguments set RAM before running.
+pub type X86Backend = Native<X86>;
+
+#[derive(Debug, Default)]
+pub struct X86;
+
+const PROLOGUE: &str = "\
+    .bss
+    .align 2
+ram:
+    .zero 65536
+
+    .text
+    .globl _start
+
+# pushes a word register onto the VM stack
+.macro vm_push reg
+    movzwl ram, %ecx
+    andl $0x7FFF, %ecx
+    movw \\reg, ram(,%rcx,2)
+    incw ram
+.endm
+
+# pops the VM stack into a 32-bit register, zero-extended
+.macro vm_pop reg
+    decw ram
+    movzwl ram, %ecx
+    andl $0x7FFF, %ecx
+    movzwl ram(,%rcx,2), \\reg
+.endm
+
+# parses an optionally negative decimal at %rsi into %eax, leaving %rsi after it
+vm_parse_int:
+    xorl %eax, %eax
+    xorl %edx, %edx
+    cmpb $45, (%rsi)            # '-'
+    jne 1f
+    incl %edx
+    incq %rsi
+1:
+    movzbl (%rsi), %ecx
+    subl $48, %ecx              # '0'
+    cmpl $9, %ecx
+    ja 2f
+    imull $10, %eax
+    addl %ecx, %eax
+    incq %rsi
+    jmp 1b
+2:
+    testl %edx, %edx
+    jz 3f
+    negl %eax
+3:
+    ret
+";
+
+impl NativeTarget for X86 {
+    const COMMENT: &'static str = "    #";
+
+    fn prologue(&self, _program: &Program) -> String {
+        PROLOGUE.to_string()
+    }
+
+    fn start_routine(&mut self, name: &str, _public: bool) -> String {
+        format!("{}:\n", name)
+    }
+
+    fn end_routine(&self) -> &'static str {
+        "    ret\n\n"
+    }
+
+    fn write(&mut self, command: &Command, program: &mut Program) -> String {
+        let pointers = segment_pointers(program);
+        match command {
+            Command::Placeholder => String::new(),
+            Command::Add => binary_op("addl"),
+            Command::Sub => binary_op("subl"),
+            Command::And => binary_op("andl"),
+            Command::Or => binary_op("orl"),
+            Command::Eq => comparison("sete"),
+            Command::Gt => comparison("setg"),
+            Command::Lt => comparison("setl"),
+            Command::Neg => unary_op("negl"),
+            Command::Not => unary_op("notl"),
+            Command::Push(MemorySegment::Constant, value) => {
+                format!("    movl ${}, %eax\n    vm_push %ax", value)
+            }
+            Command::Push(segment, index) => {
+                let (setup, operand) = segment_operand(program, segment, *index);
+                format!("{}    movw {}, %ax\n    vm_push %ax", setup, operand)
+            }
+            Command::Pop(segment, index) => {
+                let (setup, operand) = segment_operand(program, segment, *index);
+                format!("{}    vm_pop %eax\n    movw %ax, {}", setup, operand)
+            }
+            Command::Label(label) => format!("{}:", routine_label(program, label)),
+            Command::Goto(label) => format!("    jmp {}", routine_label(program, label)),
+            Command::IfGoto(label) => format!(
+                "    vm_pop %eax\n    testw %ax, %ax\n    jnz {}",
+                routine_label(program, label)
+            ),
+            Command::Function(_, nlocals) if *nlocals > 0 => [
+                format!("    movl ${}, %esi", nlocals),
+                "1:".to_string(),
+                "    xorl %eax, %eax".to_string(),
+                "    vm_push %ax".to_string(),
+                "    decl %esi".to_string(),
+                "    jnz 1b".to_string(),
+            ]
+            .join("\n"),
+            Command::Function(_, _) => String::new(),
+            Command::Call(name, nargs) => {
+                let mut lines = vec![
+                    format!("    movl ${}, %eax", program.call_site(name)),
+                    "    vm_push %ax".to_string(),
+                ];
+                for (_, pointer) in pointers {
+                    lines.push(format!("    movw ram+{}, %ax", pointer));
+                    lines.push("    vm_push %ax".to_string());
+                }
+                lines.extend([
+                    "    movzwl ram, %eax".to_string(),
+                    format!("    subl ${}, %eax", 5 + *nargs as u32),
+                    "    movw %ax, ram+4".to_string(),
+                    "    movw ram, %ax".to_string(),
+                    "    movw %ax, ram+2".to_string(),
+                    format!("    call {}", function_name(name)),
+                ]);
+                lines.join("\n")
+            }
+            Command::Return => {
+                let mut lines = vec![
+                    "    movzwl ram+2, %esi".to_string(),
+                    "    vm_pop %eax".to_string(),
+                    "    movzwl ram+4, %edx".to_string(),
+                    "    leal 1(%edx), %edi".to_string(),
+                    "    andl $0x7FFF, %edx".to_string(),
+                    "    movw %ax, ram(,%rdx,2)".to_string(),
+                    "    movw %di, ram".to_string(),
+                ];
+                // restore THAT, THIS, ARG and LCL from the frame below LCL
+                for (offset, (_, pointer)) in pointers.iter().rev().enumerate() {
+                    lines.push(format!("    leal -{}(%esi), %edx", offset + 1));
+                    lines.push("    andl $0x7FFF, %edx".to_string());
+                    lines.push("    movw ram(,%rdx,2), %ax".to_string());
+                    lines.push(format!("    movw %ax, ram+{}", pointer));
+                }
+                lines.push("    ret".to_string());
+                lines.join("\n")
+            }
+        }
+    }
+
+    fn write_halt(&mut self, label: &str, program: &mut Program) -> String {
+        format!("{}:\n    jmp vm_halt", routine_label(program, label))
+    }
+
+    fn write_set(&self, address: u16, value: u16) -> String {
+        format!("    movw ${}, ram+{}", value, 2 * address as u32)
+    }
+
+    /// `_start` sets RAM from the arguments, runs the entries, and then exits
+    fn epilogue(&self, entries: &[String], program: &Program) -> String {
+        let entries: Vec<String> = entries
+            .iter()
+            .map(|name| format!("    call {}\n", name))
+            .collect();
+        let status = 2 * *program.memory_map().stack.start() as u32;
+        format!(
+            "_start:\n\
+             \x20   movq (%rsp), %r12\n\
+             \x20   leaq 16(%rsp), %r13\n\
+             1:\n\
+             \x20   decq %r12\n\
+             \x20   jle 2f\n\
+             \x20   movq (%r13), %rsi\n\
+             \x20   addq $8, %r13\n\
+             \x20   call vm_parse_int\n\
+             \x20   cmpb $61, (%rsi)            # '='\n\
+             \x20   jne 1b\n\
+             \x20   movl %eax, %ebx\n\
+             \x20   andl $0x7FFF, %ebx\n\
+             \x20   incq %rsi\n\
+             \x20   call vm_parse_int\n\
+             \x20   movw %ax, ram(,%rbx,2)\n\
+             \x20   jmp 1b\n\
+             2:\n\
+             {}\
+             vm_halt:\n\
+             \x20   movzwl ram+{}, %edi\n\
+             \x20   movl $60, %eax              # exit\n\
+             \x20   syscall",
+            entries.concat(),
+            status
+        )
+    }
+}
+
+/// Where each segment pointer lives, as an offset into `ram`
+fn segment_pointers(program: &Program) -> [(MemorySegment, u32); 4] {
+    let pointer = program.memory_map().pointer as u32;
+    [
+        (MemorySegment::Local, 2),
+        (MemorySegment::Argument, 4),
+        (MemorySegment::This, 2 * pointer),
+        (MemorySegment::That, 2 * pointer + 2),
+    ]
+}
+
+/// Labels are scoped by the routine they appear in
+fn routine_label(program: &Program, label: &str) -> String {
+    format!("{}.{}", program.routine(), identifier(label))
+}
+
+/// The memory operand for `segment index`, with any code needed to compute it first
+fn segment_operand(program: &mut Program, segment: &MemorySegment, index: u16) -> (String, String) {
+    if let Some(address) = program.address(segment, index) {
+        return (String::new(), format!("ram+{}", 2 * address as u32));
+    }
+    let (_, pointer) = segment_pointers(program)
+        .into_iter()
+        .find(|(pointed, _)| pointed == segment)
+        .expect("constant segment has no address");
+    (
+        format!(
+            "    movzwl ram+{}, %edx\n    addl ${}, %edx\n    andl $0x7FFF, %edx\n",
+            pointer, index
+        ),
+        "ram(,%rdx,2)".to_string(),
+    )
+}
+
+fn binary_op(instruction: &str) -> String {
+    format!(
+        "    vm_pop %edx\n    vm_pop %eax\n    {} %edx, %eax\n    vm_push %ax",
+        instruction
+    )
+}
+
+fn unary_op(instruction: &str) -> String {
+    format!("    vm_pop %eax\n    {} %eax\n    vm_push %ax", instruction)
+}
+
+fn comparison(set: &str) -> String {
+    [
+        "    vm_pop %edx",
+        "    vm_pop %eax",
+        "    subw %dx, %ax",
+        "    testw %ax, %ax",
+        &format!("    {} %al", set),
+        "    movzbl %al, %eax",
+        "    negl %eax",
+        "    vm_push %ax",
+    ]
+    .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Backend;
+
+    fn write_next(backend: &mut X86Backend, commands: &[Command]) -> (usize, String) {
+        let mut code = String::new();
+        let consumed = backend.write_next(commands, &mut code).unwrap();
+        (consumed, code)
+    }
+
+    #[test]
+    fn test_labels_scoped_by_routine() {
+        let mut backend = X86Backend::new();
+        backend.set_file_context("Main".to_string());
+        let (_, code) = write_next(
+            &mut backend,
+            &[Command::Function("Main.main".to_string(), 0)],
+        );
+        assert_eq!(code, "vm_Main_dmain:\n    # function Main.main 0\n\n");
+
+        let (_, code) = write_next(&mut backend, &[Command::Goto("LOOP".to_string())]);
+        assert!(code.ends_with("    jmp vm_Main_dmain.LOOP\n"));
+    }
+
+    #[test]
+    fn test_undefined_call_fails() {
+        let mut backend = X86Backend::new();
+        backend.write(&Command::Call("Missing.f".to_string(), 0));
+
+        assert_eq!(
+            backend.check(),
+            Err("call to undefined function: Missing.f".to_string())
+        );
+    }
+}
@@ -0,0 +1,378 @@
+//! Opt-in runtime checks. A failed check jumps to a trap that records what went wrong, and
+//! in which function, in the RAM slots at `MemoryMap::traps`, then halts, so a crash can be
+//! read off the RAM.
+
+use super::{CodeWriter, CommentLevel};
+use crate::command::{Command, MemorySegment};
+use crate::hack::{Comp, Dest, Instruction, Jump};
+use std::collections::BTreeSet;
+use std::ops::RangeInclusive;
+
+/// What a failed check found, written after the id of the function it happened in, where
+/// 0 is code outside any function
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Trap {
+    StackOverflow = 1,
+    StackUnderflow = 2,
+    ThisOutOfBounds = 3,
+    ThatOutOfBounds = 4,
+    /// Division by zero in a `Math.divide` intrinsic
+    DivisionByZero = 5,
+}
+
+impl Trap {
+    fn label(self) -> &'static str {
+        match self {
+            Trap::StackOverflow => "$trap.stack_overflow",
+            Trap::StackUnderflow => "$trap.stack_underflow",
+            Trap::ThisOutOfBounds => "$trap.this_out_of_bounds",
+            Trap::ThatOutOfBounds => "$trap.that_out_of_bounds",
+            Trap::DivisionByZero => "$trap.division_by_zero",
+        }
+    }
+}
+
+/// Which checks are on, and what their traps need to report
+#[derive(Debug, Default)]
+pub(super) struct Checks {
+    pub stack: bool,
+    pub heap: bool,
+    /// Addresses `this` and `that` may also point to, e.g. the screen
+    pub allowed: Vec<RangeInclusive<u16>>,
+    /// Traps jumped to so far, with the function each was in
+    traps: BTreeSet<(Trap, usize)>,
+}
+
+/// How many values `command` leaves on the stack, once any call it makes has returned
+fn stack_effect(command: &Command) -> i32 {
+    match command {
+        Command::Push(..) => 1,
+        Command::Pop(..)
+        | Command::Add
+        | Command::Sub
+        | Command::Eq
+        | Command::Gt
+        | Command::Lt
+        | Command::And
+        | Command::Or
+        | Command::IfGoto(_) => -1,
+        Command::Call(_, nargs) => 1 - *nargs as i32,
+        _ => 0,
+    }
+}
+
+/// Whether a block of `commands` may jump away from its end, so that code appended to it
+/// doesn't always run
+pub(super) fn ends_in_jump(commands: &[Command]) -> bool {
+    matches!(commands.last(), Some(Command::IfGoto(_)))
+}
+
+impl CodeWriter {
+    /// Checks each `this` or `that` address `commands` access falls in the heap or an allowed
+    /// range
+    pub(super) fn _write_heap_checks(
+        &mut self,
+        commands: &[Command],
+        assembly: &mut Vec<Instruction>,
+    ) {
+        for command in commands {
+            let (Command::Push(segment, index) | Command::Pop(segment, index)) = command else {
+                continue;
+            };
+            let (pointer, trap) = match segment {
+                MemorySegment::This => ("THIS", Trap::ThisOutOfBounds),
+                MemorySegment::That => ("THAT", Trap::ThatOutOfBounds),
+                _ => continue,
+            };
+
+            let trap_label = self._trap_label(trap);
+            let checked_label = format!("$bounds.{}", self._next_label_id());
+            if self.comments == CommentLevel::Verbose {
+                assembly.push(Instruction::comment(format!(
+                    "check {} {} is in bounds",
+                    segment, index
+                )));
+            }
+            assembly.extend([
+                Instruction::at(pointer),
+                Instruction::set(Dest::D, Comp::M),
+                Instruction::constant(*index),
+                Instruction::set(Dest::D, Comp::DPlusA),
+                Instruction::at("R13"),
+                Instruction::set(Dest::M, Comp::D),
+                Instruction::at(trap_label.clone()),
+                Instruction::jump(Comp::D, Jump::JLT),
+            ]);
+            // `this` and `that` may point to the heap, unless allowed elsewhere
+            let ranges = std::iter::once(&self.memory_map.heap).chain(&self.checks.allowed);
+            for (i, range) in ranges.enumerate() {
+                let next_label = format!("{}.{}", checked_label, i + 1);
+                assembly.extend([
+                    Instruction::at("R13"),
+                    Instruction::set(Dest::D, Comp::M),
+                    Instruction::constant(*range.start()),
+                    Instruction::set(Dest::D, Comp::DMinusA),
+                    Instruction::at(next_label.clone()),
+                    Instruction::jump(Comp::D, Jump::JLT),
+                    Instruction::at("R13"),
+                    Instruction::set(Dest::D, Comp::M),
+                    Instruction::constant(*range.end()),
+                    Instruction::set(Dest::D, Comp::DMinusA),
+                    Instruction::at(checked_label.clone()),
+                    Instruction::jump(Comp::D, Jump::JLE),
+                    Instruction::label(next_label),
+                ]);
+            }
+            assembly.extend([
+                Instruction::at(trap_label),
+                Instruction::jump(Comp::Zero, Jump::JMP),
+                Instruction::label(checked_label),
+            ]);
+        }
+    }
+
+    /// Checks SP after `commands` if they might have moved it out of the stack: on entry to a
+    /// function, after net pushes, and after net pops
+    pub(super) fn _write_stack_check(
+        &mut self,
+        commands: &[Command],
+        assembly: &mut Vec<Instruction>,
+    ) {
+        let effect: i32 = commands.iter().map(stack_effect).sum();
+        // SP may point just past the stack when it's full, but not go further, so overflow is
+        // SP - 1 going past the end, which keeps the bound in A-instruction range
+        let stack = &self.memory_map.stack;
+        let (trap, offset, bound, jump) = match commands.first() {
+            Some(Command::Function(..)) => (Trap::StackOverflow, -1, *stack.end(), Jump::JGT),
+            _ if effect > 0 => (Trap::StackOverflow, -1, *stack.end(), Jump::JGT),
+            _ if effect < 0 => (Trap::StackUnderflow, 0, *stack.start(), Jump::JLT),
+            _ => return,
+        };
+        // checked ahead of the block, SP is offset to where the block leaves it
+        let offset = if ends_in_jump(commands) {
+            offset + effect
+        } else {
+            offset
+        };
+
+        let (first, rest) = match offset {
+            0 => (Comp::M, Comp::D),
+            _ if offset < 0 => (Comp::MMinusOne, Comp::DMinusOne),
+            _ => (Comp::MPlusOne, Comp::DPlusOne),
+        };
+        self._extend(
+            assembly,
+            [
+                Instruction::comment("check the stack pointer is within the stack"),
+                Instruction::at("SP"),
+                Instruction::set(Dest::D, first),
+            ],
+        );
+        for _ in 1..offset.unsigned_abs() {
+            assembly.push(Instruction::set(Dest::D, rest));
+        }
+        let trap_label = self._trap_label(trap);
+        assembly.extend([
+            Instruction::constant(bound),
+            Instruction::set(Dest::D, Comp::DMinusA),
+            Instruction::at(trap_label),
+            Instruction::jump(Comp::D, jump),
+        ]);
+    }
+
+    /// Checks the stack has room for `words` more values, for code that uses the RAM past
+    /// SP as scratch
+    pub(super) fn _write_room_check(&mut self, words: u16, assembly: &mut Vec<Instruction>) {
+        let trap_label = self._trap_label(Trap::StackOverflow);
+        self._extend(
+            assembly,
+            [
+                Instruction::comment("check the stack has room for the scratch words"),
+                Instruction::at("SP"),
+                Instruction::set(Dest::D, Comp::M),
+                Instruction::constant(words - 1),
+                Instruction::set(Dest::D, Comp::DPlusA),
+                Instruction::constant(*self.memory_map.stack.end()),
+                Instruction::set(Dest::D, Comp::DMinusA),
+                Instruction::at(trap_label),
+                Instruction::jump(Comp::D, Jump::JGT),
+            ],
+        );
+    }
+
+    /// Where to jump to trap in the function being written
+    pub(super) fn _trap_label(&mut self, trap: Trap) -> String {
+        let function_id = self._function_id();
+        self.checks.traps.insert((trap, function_id));
+        format!("{}.{}", trap.label(), function_id)
+    }
+
+    /// The traps the checks jump to, and the table of function ids they report
+    pub(super) fn _write_traps(&self, assembly: &mut Vec<Instruction>) {
+        if self.checks.traps.is_empty() {
+            return;
+        }
+
+        assembly.push(Instruction::comment("trap function ids"));
+        assembly.push(Instruction::comment("0: (none)"));
+        for (i, name) in self.functions.iter().enumerate() {
+            assembly.push(Instruction::comment(format!("{}: {}", i + 1, name)));
+        }
+
+        for (trap, function_id) in &self.checks.traps {
+            assembly.extend([
+                Instruction::label(format!("{}.{}", trap.label(), function_id)),
+                Instruction::constant(*function_id as u16),
+                Instruction::set(Dest::D, Comp::A),
+                Instruction::at(trap.label()),
+                Instruction::jump(Comp::Zero, Jump::JMP),
+            ]);
+        }
+
+        let traps: BTreeSet<Trap> = self.checks.traps.iter().map(|(trap, _)| *trap).collect();
+        let slots = self.memory_map.traps;
+        for trap in traps {
+            assembly.extend([
+                Instruction::label(trap.label()),
+                Instruction::constant(slots),
+                Instruction::set(Dest::M, Comp::D),
+                Instruction::constant(trap as u16),
+                Instruction::set(Dest::D, Comp::A),
+                Instruction::constant(slots + 1),
+                Instruction::set(Dest::M, Comp::D),
+                Instruction::at("$trap.halt"),
+                Instruction::jump(Comp::Zero, Jump::JMP),
+            ]);
+        }
+        assembly.extend([
+            Instruction::label("$trap.halt"),
+            Instruction::at("$trap.halt"),
+            Instruction::jump(Comp::Zero, Jump::JMP),
+        ]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Backend;
+    use crate::hack::{self, emulator, MemoryMap};
+    use crate::translator;
+
+    fn checked() -> CodeWriter {
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_stack_checks(true);
+        codewriter
+    }
+
+    #[test]
+    fn test_stack_checked_by_net_effect() {
+        let mut codewriter = checked();
+
+        let (_, push) = codewriter.write_next(&[Command::Push(MemorySegment::Local, 0)]);
+        assert!(hack::print(&push)
+            .ends_with("@SP\nD=M-1\n@1791\nD=D-A\n@$trap.stack_overflow.0\nD;JGT"));
+        let (_, add) = codewriter.write_next(&[Command::Add]);
+        assert!(
+            hack::print(&add).ends_with("@SP\nD=M\n@256\nD=D-A\n@$trap.stack_underflow.0\nD;JLT")
+        );
+        let (_, neg) = codewriter.write_next(&[Command::Neg]);
+        assert!(!hack::print(&neg).contains("$trap"));
+    }
+
+    #[test]
+    fn test_traps_report_function() {
+        let mut codewriter = checked();
+        codewriter.write_next(&[Command::Function("Main.main".to_string(), 1)]);
+        codewriter.write_next(&[Command::Function("Main.f".to_string(), 0)]);
+
+        let mut traps = String::new();
+        Backend::write_epilogue(&mut codewriter, &mut traps).unwrap();
+        assert!(
+            traps.contains("// trap function ids\n// 0: (none)\n// 1: Main.main\n// 2: Main.f\n")
+        );
+        assert!(traps.contains("($trap.stack_overflow.2)\n@2\nD=A\n@$trap.stack_overflow\n0;JMP\n"));
+        assert!(traps.contains("@2046\nM=D\n@1\nD=A\n@2047\nM=D\n"));
+        assert!(Backend::check(&mut codewriter).is_ok());
+    }
+
+    #[test]
+    fn test_heap_checks_precede_access() {
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_heap_checks(true);
+        codewriter.allow_heap_access(16384..=24575).unwrap();
+        assert!(codewriter.allow_heap_access(40000..=50000).is_err());
+
+        let (_, pop) = codewriter.write_next(&[Command::Pop(MemorySegment::That, 2)]);
+        let asm = hack::print(&pop);
+        assert!(asm.starts_with(
+            "// pop that 2\n@THAT\nD=M\n@2\nD=D+A\n@R13\nM=D\n@$trap.that_out_of_bounds.0\nD;JLT\n"
+        ));
+        assert!(asm.contains("@2048\nD=D-A\n@$bounds.1.1\nD;JLT\n"));
+        assert!(asm.contains("@24575\nD=D-A\n@$bounds.1\nD;JLE\n($bounds.1.2)\n"));
+        assert!(asm.contains("@$trap.that_out_of_bounds.0\n0;JMP\n($bounds.1)\n"));
+
+        let (_, push) = codewriter.write_next(&[Command::Push(MemorySegment::Local, 2)]);
+        assert!(!hack::print(&push).contains("$bounds"));
+    }
+
+    /// Runs `source` on `ram`, with SP at the bottom of the stack
+    fn run(mut codewriter: CodeWriter, source: &str, ram: &mut [i16]) {
+        let inputs = vec![("Main.vm".to_string(), source.to_string())];
+        let (asm, _) = translator::translate_with(&mut codewriter, inputs, false).unwrap();
+        ram[0] = 256;
+        assert!(emulator::run(&asm, ram, 1000));
+    }
+
+    #[test]
+    fn test_whole_default_heap_allowed() {
+        let traps = MemoryMap::default().traps as usize;
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_heap_checks(true);
+        let mut ram = vec![0i16; 32768];
+        ram[16383] = 42;
+        run(
+            codewriter,
+            "push constant 16383\npop pointer 1\npush that 0\npop temp 0",
+            &mut ram,
+        );
+        assert_eq!(ram[5], 42);
+        assert_eq!(ram[traps + 1], 0);
+
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_heap_checks(true);
+        let mut ram = vec![0i16; 32768];
+        run(
+            codewriter,
+            "push constant 16384\npop pointer 1\npush that 0\npop temp 0",
+            &mut ram,
+        );
+        assert_eq!(ram[traps + 1], Trap::ThatOutOfBounds as i16);
+    }
+
+    #[test]
+    fn test_taken_branch_checked() {
+        let traps = MemoryMap::default().traps as usize;
+        for source in [
+            "if-goto END\npush constant 1\nlabel END",
+            "push constant 1\neq\nif-goto END\npush constant 1\nlabel END",
+        ] {
+            let mut ram = vec![0i16; 32768];
+            // whatever lies below the stack makes the branch taken
+            ram[254] = 1;
+            ram[255] = 1;
+            run(checked(), source, &mut ram);
+            assert_eq!(ram[traps + 1], Trap::StackUnderflow as i16, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_unchecked_by_default() {
+        let mut codewriter = CodeWriter::new();
+        codewriter.write_next(&[Command::Function("Main.main".to_string(), 1)]);
+
+        let mut traps = String::new();
+        Backend::write_epilogue(&mut codewriter, &mut traps).unwrap();
+        assert!(!traps.contains("$trap"));
+    }
+}
@@ -0,0 +1,521 @@
+//! Calls to a few OS functions are replaced with hand-written Hack that leaves the stack as
+//! the call would have, without the cost of a frame or of the VM-level implementation. Short
+//! ones are written inline; longer ones are shared routines, jumped to with the return
+//! address in D, that use R13-R15 as scratch. Divide also uses the stack past SP, which
+//! its call sites check there is room for.
+
+use super::checks::Trap;
+use super::CodeWriter;
+use crate::hack::{Comp, Dest, Instruction, Jump};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Intrinsic {
+    Multiply,
+    Divide,
+    Peek,
+    Poke,
+}
+
+/// The OS functions with intrinsics, by name and number of arguments
+const REGISTRY: [(&str, u16, Intrinsic); 4] = [
+    ("Math.multiply", 2, Intrinsic::Multiply),
+    ("Math.divide", 2, Intrinsic::Divide),
+    ("Memory.peek", 1, Intrinsic::Peek),
+    ("Memory.poke", 2, Intrinsic::Poke),
+];
+
+impl Intrinsic {
+    pub fn find(name: &str, nargs: u16) -> Option<Intrinsic> {
+        REGISTRY
+            .iter()
+            .find(|(function, arity, _)| *function == name && *arity == nargs)
+            .map(|(_, _, intrinsic)| *intrinsic)
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Intrinsic::Multiply => "$intrinsic.multiply",
+            Intrinsic::Divide => "$intrinsic.divide",
+            Intrinsic::Peek => "$intrinsic.peek",
+            Intrinsic::Poke => "$intrinsic.poke",
+        }
+    }
+}
+
+/// How many words past SP the divide routine uses for the multiples of `|y|`: one per bit
+/// of `|x|` at most
+const DIVIDE_SCRATCH: u16 = 16;
+
+/// A label inside `intrinsic`'s routine
+fn local(intrinsic: Intrinsic, suffix: &str) -> String {
+    format!("{}.{}", intrinsic.label(), suffix)
+}
+
+impl CodeWriter {
+    /// Writes a call to `intrinsic` in place of calling the OS function
+    pub(super) fn _write_intrinsic(
+        &mut self,
+        intrinsic: Intrinsic,
+        assembly: &mut Vec<Instruction>,
+    ) {
+        match intrinsic {
+            Intrinsic::Peek => self._extend(
+                assembly,
+                [
+                    Instruction::comment("replace the address with what it holds"),
+                    Instruction::at("SP"),
+                    Instruction::set(Dest::A, Comp::MMinusOne),
+                    Instruction::set(Dest::A, Comp::M),
+                    Instruction::set(Dest::D, Comp::M),
+                    Instruction::at("SP"),
+                    Instruction::set(Dest::A, Comp::MMinusOne),
+                    Instruction::set(Dest::M, Comp::D),
+                ],
+            ),
+            Intrinsic::Poke => self._extend(
+                assembly,
+                [
+                    Instruction::comment("pop the value"),
+                    Instruction::at("SP"),
+                    Instruction::set(Dest::AM, Comp::MMinusOne),
+                    Instruction::set(Dest::D, Comp::M),
+                    Instruction::comment("store it at the address"),
+                    Instruction::at("SP"),
+                    Instruction::set(Dest::A, Comp::MMinusOne),
+                    Instruction::set(Dest::A, Comp::M),
+                    Instruction::set(Dest::M, Comp::D),
+                    Instruction::comment("and return 0 in the address's place"),
+                    Instruction::at("SP"),
+                    Instruction::set(Dest::A, Comp::MMinusOne),
+                    Instruction::set(Dest::M, Comp::Zero),
+                ],
+            ),
+            Intrinsic::Multiply | Intrinsic::Divide => {
+                if intrinsic == Intrinsic::Divide {
+                    let trap_label = self._trap_label(Trap::DivisionByZero);
+                    self._extend(
+                        assembly,
+                        [
+                            Instruction::comment("trap if the divisor is 0"),
+                            Instruction::at("SP"),
+                            Instruction::set(Dest::A, Comp::MMinusOne),
+                            Instruction::set(Dest::D, Comp::M),
+                            Instruction::at(trap_label),
+                            Instruction::jump(Comp::D, Jump::JEQ),
+                        ],
+                    );
+                    if self.checks.stack {
+                        self._write_room_check(DIVIDE_SCRATCH, assembly);
+                    }
+                }
+                self.routines.insert(intrinsic);
+                let return_label = format!("$intrinsic.ret.{}", self._next_label_id());
+                self._extend(
+                    assembly,
+                    [
+                        Instruction::comment("jump to the shared routine, returning here"),
+                        Instruction::at(return_label.clone()),
+                        Instruction::set(Dest::D, Comp::A),
+                        Instruction::at(intrinsic.label()),
+                        Instruction::jump(Comp::Zero, Jump::JMP),
+                        Instruction::label(return_label),
+                    ],
+                );
+            }
+        }
+    }
+
+    /// The shared routines the intrinsics written so far jump to
+    pub(super) fn _write_routines(&self, assembly: &mut Vec<Instruction>) {
+        for intrinsic in &self.routines {
+            match intrinsic {
+                Intrinsic::Multiply => self._extend(assembly, write_multiply()),
+                Intrinsic::Divide => self._extend(assembly, write_divide()),
+                Intrinsic::Peek | Intrinsic::Poke => {}
+            }
+        }
+    }
+}
+
+/// Replaces `x y` with `x * y`, adding `x` shifted by each bit set in `y`
+fn write_multiply() -> Vec<Instruction> {
+    let intrinsic = Intrinsic::Multiply;
+    vec![
+        Instruction::comment("Math.multiply"),
+        Instruction::label(intrinsic.label()),
+        Instruction::at("R15"),
+        Instruction::set(Dest::M, Comp::D),
+        Instruction::comment("pop y into R14"),
+        Instruction::at("SP"),
+        Instruction::set(Dest::AM, Comp::MMinusOne),
+        Instruction::set(Dest::D, Comp::M),
+        Instruction::at("R14"),
+        Instruction::set(Dest::M, Comp::D),
+        Instruction::comment("the bit of y to test goes where y was"),
+        Instruction::at("SP"),
+        Instruction::set(Dest::A, Comp::M),
+        Instruction::set(Dest::M, Comp::One),
+        Instruction::comment("x goes in R13, and the product where x was"),
+        Instruction::at("SP"),
+        Instruction::set(Dest::A, Comp::MMinusOne),
+        Instruction::set(Dest::D, Comp::M),
+        Instruction::at("R13"),
+        Instruction::set(Dest::M, Comp::D),
+        Instruction::at("SP"),
+        Instruction::set(Dest::A, Comp::MMinusOne),
+        Instruction::set(Dest::M, Comp::Zero),
+        Instruction::label(local(intrinsic, "loop")),
+        Instruction::at("SP"),
+        Instruction::set(Dest::A, Comp::M),
+        Instruction::set(Dest::D, Comp::M),
+        Instruction::at("R14"),
+        Instruction::set(Dest::D, Comp::DAndM),
+        Instruction::at(local(intrinsic, "skip")),
+        Instruction::jump(Comp::D, Jump::JEQ),
+        Instruction::at("R13"),
+        Instruction::set(Dest::D, Comp::M),
+        Instruction::at("SP"),
+        Instruction::set(Dest::A, Comp::MMinusOne),
+        Instruction::set(Dest::M, Comp::DPlusM),
+        Instruction::label(local(intrinsic, "skip")),
+        Instruction::comment("shift x and the bit left, until the bit falls off the end"),
+        Instruction::at("R13"),
+        Instruction::set(Dest::D, Comp::M),
+        Instruction::set(Dest::M, Comp::DPlusM),
+        Instruction::at("SP"),
+        Instruction::set(Dest::A, Comp::M),
+        Instruction::set(Dest::D, Comp::M),
+        Instruction::set(Dest::MD, Comp::DPlusM),
+        Instruction::at(local(intrinsic, "loop")),
+        Instruction::jump(Comp::D, Jump::JNE),
+        Instruction::at("R15"),
+        Instruction::set(Dest::A, Comp::M),
+        Instruction::jump(Comp::Zero, Jump::JMP),
+    ]
+}
+
+/// Replaces `x y` with `x / y`, rounded towards 0, by long division of `|x|` by `|y|`: the
+/// multiples `|y| * 2^k` that fit in `|x|` are pushed, then popped off to subtract
+fn write_divide() -> Vec<Instruction> {
+    let intrinsic = Intrinsic::Divide;
+    // the slots x and y were passed in, SP - 2 and SP - 1 until the multiples are pushed
+    let sign = || {
+        vec![
+            Instruction::at("SP"),
+            Instruction::set(Dest::A, Comp::MMinusOne),
+            Instruction::set(Dest::A, Comp::AMinusOne),
+        ]
+    };
+    let return_address = || {
+        vec![
+            Instruction::at("SP"),
+            Instruction::set(Dest::A, Comp::MMinusOne),
+        ]
+    };
+    [
+        vec![
+            Instruction::comment("Math.divide"),
+            Instruction::label(intrinsic.label()),
+            Instruction::at("R15"),
+            Instruction::set(Dest::M, Comp::D),
+            Instruction::comment("y goes in R14, and the return address where y was"),
+        ],
+        return_address(),
+        vec![
+            Instruction::set(Dest::D, Comp::M),
+            Instruction::at("R14"),
+            Instruction::set(Dest::M, Comp::D),
+            Instruction::at("R15"),
+            Instruction::set(Dest::D, Comp::M),
+        ],
+        return_address(),
+        vec![
+            Instruction::set(Dest::M, Comp::D),
+            Instruction::comment("x goes in R13, and whether to negate the quotient where x was"),
+        ],
+        sign(),
+        vec![
+            Instruction::set(Dest::D, Comp::M),
+            Instruction::at("R13"),
+            Instruction::set(Dest::M, Comp::D),
+        ],
+        sign(),
+        vec![
+            Instruction::set(Dest::M, Comp::Zero),
+            Instruction::at("R13"),
+            Instruction::set(Dest::D, Comp::M),
+            Instruction::at(local(intrinsic, "x_positive")),
+            Instruction::jump(Comp::D, Jump::JGE),
+            Instruction::at("R13"),
+            Instruction::set(Dest::M, Comp::NegM),
+        ],
+        sign(),
+        vec![
+            Instruction::set(Dest::M, Comp::NotM),
+            Instruction::label(local(intrinsic, "x_positive")),
+            Instruction::at("R14"),
+            Instruction::set(Dest::D, Comp::M),
+            Instruction::at(local(intrinsic, "y_positive")),
+            Instruction::jump(Comp::D, Jump::JGE),
+            Instruction::at("R14"),
+            Instruction::set(Dest::M, Comp::NegM),
+        ],
+        sign(),
+        vec![
+            Instruction::set(Dest::M, Comp::NotM),
+            Instruction::label(local(intrinsic, "y_positive")),
+            Instruction::comment("the quotient builds up in R15"),
+            Instruction::at("R15"),
+            Instruction::set(Dest::M, Comp::Zero),
+            Instruction::comment("push |y|, then double it while that fits in |x|"),
+            Instruction::at("R14"),
+            Instruction::set(Dest::D, Comp::M),
+            Instruction::at("SP"),
+            Instruction::set(Dest::A, Comp::M),
+            Instruction::set(Dest::M, Comp::D),
+            Instruction::at("SP"),
+            Instruction::set(Dest::M, Comp::MPlusOne),
+            Instruction::label(local(intrinsic, "double")),
+            Instruction::at("SP"),
+            Instruction::set(Dest::A, Comp::MMinusOne),
+            Instruction::set(Dest::D, Comp::M),
+            Instruction::at("R13"),
+            Instruction::set(Dest::D, Comp::MMinusD),
+            Instruction::at(local(intrinsic, "halve")),
+            Instruction::jump(Comp::D, Jump::JLT),
+            Instruction::at("SP"),
+            Instruction::set(Dest::A, Comp::MMinusOne),
+            Instruction::set(Dest::D, Comp::DMinusM),
+            Instruction::at(local(intrinsic, "halve")),
+            Instruction::jump(Comp::D, Jump::JLT),
+            Instruction::at("SP"),
+            Instruction::set(Dest::A, Comp::MMinusOne),
+            Instruction::set(Dest::D, Comp::M),
+            Instruction::set(Dest::D, Comp::DPlusM),
+            Instruction::set(Dest::A, Comp::APlusOne),
+            Instruction::set(Dest::M, Comp::D),
+            Instruction::at("SP"),
+            Instruction::set(Dest::M, Comp::MPlusOne),
+            Instruction::at(local(intrinsic, "double")),
+            Instruction::jump(Comp::Zero, Jump::JMP),
+            Instruction::comment("pop each multiple, subtracting it from |x| if it fits"),
+            Instruction::label(local(intrinsic, "halve")),
+            Instruction::at("R15"),
+            Instruction::set(Dest::D, Comp::M),
+            Instruction::set(Dest::M, Comp::DPlusM),
+            Instruction::at("SP"),
+            Instruction::set(Dest::A, Comp::MMinusOne),
+            Instruction::set(Dest::D, Comp::M),
+            Instruction::at("R13"),
+            Instruction::set(Dest::D, Comp::MMinusD),
+            Instruction::at(local(intrinsic, "next")),
+            Instruction::jump(Comp::D, Jump::JLT),
+            Instruction::at("R13"),
+            Instruction::set(Dest::M, Comp::D),
+            Instruction::at("R15"),
+            Instruction::set(Dest::M, Comp::MPlusOne),
+            Instruction::label(local(intrinsic, "next")),
+            Instruction::at("SP"),
+            Instruction::set(Dest::AM, Comp::MMinusOne),
+            Instruction::set(Dest::D, Comp::M),
+            Instruction::at("R14"),
+            Instruction::set(Dest::D, Comp::DMinusM),
+            Instruction::at(local(intrinsic, "halve")),
+            Instruction::jump(Comp::D, Jump::JNE),
+            Instruction::comment("negate the quotient if x and y had different signs"),
+        ],
+        sign(),
+        vec![
+            Instruction::set(Dest::D, Comp::M),
+            Instruction::at(local(intrinsic, "positive")),
+            Instruction::jump(Comp::D, Jump::JEQ),
+            Instruction::at("R15"),
+            Instruction::set(Dest::M, Comp::NegM),
+            Instruction::label(local(intrinsic, "positive")),
+            Instruction::comment("pop the return address, and leave the quotient in x's place"),
+            Instruction::at("SP"),
+            Instruction::set(Dest::AM, Comp::MMinusOne),
+            Instruction::set(Dest::D, Comp::M),
+            Instruction::at("R14"),
+            Instruction::set(Dest::M, Comp::D),
+            Instruction::at("R15"),
+            Instruction::set(Dest::D, Comp::M),
+            Instruction::at("SP"),
+            Instruction::set(Dest::A, Comp::MMinusOne),
+            Instruction::set(Dest::M, Comp::D),
+            Instruction::at("R14"),
+            Instruction::set(Dest::A, Comp::M),
+            Instruction::jump(Comp::Zero, Jump::JMP),
+        ],
+    ]
+    .concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Backend;
+    use crate::codewriter::CommentLevel;
+    use crate::command::Command;
+    use crate::hack::{self, emulator, MemoryMap};
+    use crate::translator;
+    use std::collections::HashSet;
+
+    fn call(codewriter: &mut CodeWriter, name: &str, nargs: u16) -> String {
+        hack::print(&codewriter.write(&Command::Call(name.to_string(), nargs)))
+    }
+
+    /// Pushes `value`, which `push constant` alone can't do for negatives
+    fn push(value: i16) -> String {
+        match value {
+            i16::MIN => "push constant 32767\nneg\npush constant 1\nsub\n".to_string(),
+            _ if value < 0 => format!("push constant {}\nneg\n", -value),
+            _ => format!("push constant {}\n", value),
+        }
+    }
+
+    /// Runs `name(a, b)` until the program halts, returning the RAM
+    fn run(name: &str, a: i16, b: i16) -> Vec<i16> {
+        run_with(CodeWriter::new(), name, a, b)
+    }
+
+    fn run_with(mut codewriter: CodeWriter, name: &str, a: i16, b: i16) -> Vec<i16> {
+        let source = format!("{}{}call {} 2\npop temp 0\n", push(a), push(b), name);
+        let inputs = vec![("Main.vm".to_string(), source)];
+        let (asm, _) = translator::translate_with(&mut codewriter, inputs, false).unwrap();
+        assert!(asm.contains("$intrinsic."));
+
+        let mut ram = vec![0i16; 32768];
+        ram[0] = 256;
+        assert!(
+            emulator::run(&asm, &mut ram, 10_000),
+            "{}({}, {}) didn't halt",
+            name,
+            a,
+            b
+        );
+        ram
+    }
+
+    const OPERANDS: [i16; 13] = [
+        0, 1, -1, 2, -2, 7, -7, 100, -300, 181, 32767, -32767, -32768,
+    ];
+
+    #[test]
+    fn test_multiply_results() {
+        for a in OPERANDS {
+            for b in OPERANDS {
+                let ram = run("Math.multiply", a, b);
+                assert_eq!(ram[5], a.wrapping_mul(b), "{} * {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_divide_results() {
+        for a in OPERANDS {
+            for b in OPERANDS.into_iter().filter(|b| *b != 0) {
+                let ram = run("Math.divide", a, b);
+                assert_eq!(ram[5], a.wrapping_div(b), "{} / {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_division_by_zero_traps() {
+        let map = MemoryMap::default();
+        for a in [0, 7, -32768] {
+            let ram = run("Math.divide", a, 0);
+            assert_eq!(ram[map.traps as usize + 1], Trap::DivisionByZero as i16);
+            assert_eq!(ram[map.traps as usize], 0);
+        }
+    }
+
+    #[test]
+    fn test_divide_scratch_checked() {
+        let map = MemoryMap {
+            stack: 256..=273,
+            ..Default::default()
+        };
+        let checked = || {
+            let mut codewriter = CodeWriter::new();
+            codewriter.set_stack_checks(true);
+            codewriter.set_memory_map(map.clone()).unwrap();
+            codewriter
+        };
+
+        // 2 arguments and 16 multiples of 1 fill the stack exactly
+        let ram = run_with(checked(), "Math.divide", -32768, 1);
+        assert_eq!(ram[5], -32768);
+        assert_eq!(ram[map.traps as usize + 1], 0);
+        assert_ne!(ram[273], 0);
+        assert_eq!(ram[274], 0);
+
+        let mut codewriter = checked();
+        codewriter
+            .set_memory_map(MemoryMap {
+                stack: 256..=272,
+                ..map.clone()
+            })
+            .unwrap();
+        let ram = run_with(codewriter, "Math.divide", -32768, 1);
+        assert_eq!(ram[map.traps as usize + 1], Trap::StackOverflow as i16);
+        assert_eq!(ram[273], 0);
+    }
+
+    #[test]
+    fn test_peek_and_poke_inline() {
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_comment_level(CommentLevel::None);
+
+        assert_eq!(
+            call(&mut codewriter, "Memory.peek", 1),
+            "@SP\nA=M-1\nA=M\nD=M\n@SP\nA=M-1\nM=D"
+        );
+        assert!(!call(&mut codewriter, "Memory.poke", 2).contains("Memory.poke"));
+
+        let mut epilogue = String::new();
+        Backend::write_epilogue(&mut codewriter, &mut epilogue).unwrap();
+        assert!(!epilogue.contains("$intrinsic"));
+    }
+
+    #[test]
+    fn test_routines_shared() {
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_comment_level(CommentLevel::None);
+
+        assert_eq!(
+            call(&mut codewriter, "Math.multiply", 2),
+            "@$intrinsic.ret.1\nD=A\n@$intrinsic.multiply\n0;JMP\n($intrinsic.ret.1)"
+        );
+        call(&mut codewriter, "Math.multiply", 2);
+        assert!(call(&mut codewriter, "Math.divide", 2)
+            .starts_with("@SP\nA=M-1\nD=M\n@$trap.division_by_zero.0\nD;JEQ\n"));
+
+        let mut epilogue = String::new();
+        Backend::write_epilogue(&mut codewriter, &mut epilogue).unwrap();
+        assert_eq!(epilogue.matches("($intrinsic.multiply)\n").count(), 1);
+        assert_eq!(epilogue.matches("($intrinsic.divide)\n").count(), 1);
+        assert!(epilogue.contains("($trap.division_by_zero)\n"));
+        assert!(Backend::check(&mut codewriter).is_ok());
+    }
+
+    #[test]
+    fn test_calls_kept_without_intrinsics() {
+        let mut codewriter = CodeWriter::new();
+        assert!(call(&mut codewriter, "Math.multiply", 3).contains("@Math.multiply\n"));
+
+        codewriter.set_intrinsics(false);
+        assert!(call(&mut codewriter, "Memory.peek", 1).contains("@Memory.peek\n"));
+    }
+
+    #[test]
+    fn test_calls_kept_to_defined_functions() {
+        let mut codewriter = CodeWriter::new();
+        let defined = HashSet::from(["Math.multiply".to_string()]);
+        Backend::set_defined_functions(&mut codewriter, &defined);
+
+        assert!(call(&mut codewriter, "Math.multiply", 2).contains("@Math.multiply\n"));
+        assert!(!call(&mut codewriter, "Math.divide", 2).contains("@Math.divide\n"));
+    }
+}
@@ -1,16 +1,97 @@
+use crate::backend::Backend;
 use crate::command::{Command, MemorySegment};
+use crate::hack::{Comp, Dest, Instruction, Jump, MemoryMap, MAX_CONSTANT, ROM_SIZE};
+use crate::verifier::Verifier;
+use std::collections::{BTreeSet, HashSet};
 use std::fmt;
+use std::ops::RangeInclusive;
+
+mod checks;
+mod intrinsics;
+mod profile;
+mod size;
+mod tiles;
+
+use checks::Checks;
+pub use checks::Trap;
+use intrinsics::Intrinsic;
+pub use size::SizeReport;
+use size::Sizes;
 
 #[derive(Debug, Default)]
 pub struct CodeWriter {
     label_counter: usize,
+    return_counter: usize,
     context: Context,
+    comments: CommentLevel,
+    verifier: Verifier,
+    checks: Checks,
+    profiling: bool,
+    /// Function names, in order of their ids from 1
+    functions: Vec<String>,
+    bootstrap: BootstrapConfig,
+    bootstrapped: bool,
+    memory_map: MemoryMap,
+    sizes: Sizes,
+    rom_budget: usize,
+    intrinsics: bool,
+    /// Functions the program defines itself, whose calls intrinsics never replace
+    defined: HashSet<String>,
+    /// Intrinsics' shared routines that calls have been written to
+    routines: BTreeSet<Intrinsic>,
+    /// Each block is written into this and printed, so once it has grown, writing allocates
+    /// little beyond the labels
+    assembly: Vec<Instruction>,
+}
+
+/// How much commentary is written alongside the generated assembly
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CommentLevel {
+    /// Bare instructions, for the smallest diffable output
+    None,
+    /// A `// {command}` header in front of each block
+    #[default]
+    Command,
+    /// Headers with the VM source location, plus the templates' own comments
+    Verbose,
+}
+
+/// How the bootstrap sets up the machine, and what it calls
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootstrapConfig {
+    pub entry: String,
+    /// `None` starts the stack at the memory map's
+    pub sp: Option<u16>,
+    /// Initial LCL, ARG, THIS and THAT; `None` leaves a pointer as the machine starts with it
+    pub lcl: Option<u16>,
+    pub arg: Option<u16>,
+    pub this: Option<u16>,
+    pub that: Option<u16>,
+    /// End in a loop when the entry function returns, or, without a bootstrap, when
+    /// execution reaches the end of the program, rather than running off into empty ROM
+    pub halt: bool,
+}
+
+impl Default for BootstrapConfig {
+    fn default() -> Self {
+        BootstrapConfig {
+            entry: "Sys.init".to_string(),
+            sp: None,
+            lcl: None,
+            arg: None,
+            this: None,
+            that: None,
+            halt: true,
+        }
+    }
 }
 
 #[derive(Debug, Default)]
 struct Context {
     file: String,
     function: String,
+    source: String,
+    line: usize,
 }
 
 impl fmt::Display for Context {
@@ -27,332 +108,743 @@ impl CodeWriter {
     pub fn new() -> Self {
         CodeWriter {
             label_counter: 1,
+            return_counter: 1,
             context: Context::default(),
+            comments: CommentLevel::default(),
+            verifier: Verifier::default(),
+            checks: Checks::default(),
+            profiling: false,
+            functions: Vec::new(),
+            bootstrap: BootstrapConfig::default(),
+            bootstrapped: false,
+            memory_map: MemoryMap::default(),
+            sizes: Sizes::default(),
+            rom_budget: ROM_SIZE,
+            intrinsics: true,
+            defined: HashSet::new(),
+            routines: BTreeSet::new(),
+            assembly: Vec::new(),
+        }
+    }
+
+    pub fn set_comment_level(&mut self, level: CommentLevel) {
+        self.comments = level;
+    }
+
+    /// Checks SP stays within the stack, trapping with `Trap::StackOverflow` or
+    /// `Trap::StackUnderflow` if it doesn't
+    pub fn set_stack_checks(&mut self, enabled: bool) {
+        self.checks.stack = enabled;
+    }
+
+    /// Checks `this` and `that` accesses stay within the heap, or one of the ranges passed to
+    /// `allow_heap_access`, trapping with `Trap::ThisOutOfBounds` or `Trap::ThatOutOfBounds`
+    /// if they don't
+    pub fn set_heap_checks(&mut self, enabled: bool) {
+        self.checks.heap = enabled;
+    }
+
+    pub fn allow_heap_access(&mut self, addresses: RangeInclusive<u16>) -> Result<(), String> {
+        if addresses.is_empty() || *addresses.end() > MAX_CONSTANT {
+            return Err(format!(
+                "Heap access range {}-{} is empty or goes past {}",
+                addresses.start(),
+                addresses.end(),
+                MAX_CONSTANT
+            ));
         }
+        self.checks.allowed.push(addresses);
+        Ok(())
+    }
+
+    pub fn set_bootstrap(&mut self, config: BootstrapConfig) {
+        self.bootstrap = config;
+    }
+
+    /// Lays out the segments for a Hack-like machine, rejecting maps whose regions overlap.
+    /// Assemble the output with the same map.
+    pub fn set_memory_map(&mut self, memory_map: MemoryMap) -> Result<(), String> {
+        memory_map.validate()?;
+        self.memory_map = memory_map;
+        Ok(())
+    }
+
+    /// Fails the program once it takes more than `budget` instructions
+    pub fn set_rom_budget(&mut self, budget: usize) {
+        self.rom_budget = budget;
+    }
+
+    /// Replaces calls to `Math.multiply`, `Math.divide`, `Memory.peek` and `Memory.poke` with
+    /// hand-written code, except for those the program defines itself
+    pub fn set_intrinsics(&mut self, enabled: bool) {
+        self.intrinsics = enabled;
+    }
+
+    /// Counts calls to each function in RAM, from the top of `MemoryMap::counters` down
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profiling = enabled;
     }
 
     pub fn set_file_context(&mut self, filename: String) {
-        self.context.file = filename;
+        self.context.file = mangle(&filename);
+        // until a function starts, the file's code is outside any
+        self.context.function = String::new();
+        self.return_counter = 1;
+    }
+
+    /// Records where the next command(s) came from, for verbose comments
+    pub fn set_source_context(&mut self, source: &str, line: usize) {
+        self.context.source.clear();
+        self.context.source.push_str(source);
+        self.context.line = line;
     }
 
     fn _set_function_context(&mut self, name: String) {
-        self.context.function = name;
-    }
-
-    pub fn write_bootstrap(&mut self) -> String {
-        [
-            // initialize stack pointer
-            "@256",
-            "D=A",
-            "@SP",
-            "M=D",
-            // start executing entrypoint
-            &self.write_call("Sys.init", 0),
-        ]
-        .join("\n")
-    }
-
-    pub fn write(&mut self, command: &Command) -> String {
-        let debug_comment = format!("// {}", command);
-
-        let assembly = match command {
-            Command::Add => self.write_add(),
-            Command::Sub => self.write_sub(),
-            Command::Neg => self.write_neg(),
-            Command::Eq => self.write_eq(),
-            Command::Gt => self.write_gt(),
-            Command::Lt => self.write_lt(),
-            Command::And => self.write_and(),
-            Command::Or => self.write_or(),
-            Command::Not => self.write_not(),
-            Command::Push(segment, address) => self.write_push(segment, *address),
-            Command::Pop(segment, address) => self.write_pop(segment, *address),
-            Command::Label(value) => self.write_label(value),
-            Command::Goto(value) => self.write_goto(value),
-            Command::IfGoto(value) => self.write_ifgoto(value),
-            Command::Function(name, nargs) => self.write_function(name, *nargs),
-            Command::Call(name, nargs) => self.write_call(name, *nargs),
-            Command::Return => self.write_return(),
-            _ => "// Not implemented yet".to_string(),
-        };
+        self.functions.push(name.clone());
+        self.context.function = mangle(&name);
+        self.return_counter = 1;
+    }
+
+    pub fn write_bootstrap(&mut self) -> Vec<Instruction> {
+        let mut assembly = Vec::new();
+        self._write_bootstrap(&mut assembly);
+        assembly
+    }
+
+    fn _write_bootstrap(&mut self, assembly: &mut Vec<Instruction>) {
+        self.bootstrapped = true;
+        // the bootstrap's return point is named like any caller's, in the reserved namespace
+        self.context.function = "$bootstrap".to_string();
+        let config = self.bootstrap.clone();
+        if self.comments != CommentLevel::None {
+            assembly.push(Instruction::comment("bootstrap"));
+        }
+        self._extend(assembly, [Instruction::comment("initialize stack pointer")]);
+        let sp = config.sp.unwrap_or(*self.memory_map.stack.start());
+        self._write_pointer("SP", sp, assembly);
+        let pointers = [
+            ("LCL", config.lcl),
+            ("ARG", config.arg),
+            ("THIS", config.this),
+            ("THAT", config.that),
+        ];
+        for (pointer, value) in pointers {
+            if let Some(value) = value {
+                self._write_pointer(pointer, value, assembly);
+            }
+        }
+        self._extend(
+            assembly,
+            [Instruction::comment("start executing entrypoint")],
+        );
+        self.write_call(&config.entry, 0, assembly);
+        if config.halt {
+            self._write_halt(assembly);
+        }
+        self.context.function = String::new();
+    }
+
+    pub fn write(&mut self, command: &Command) -> Vec<Instruction> {
+        let mut assembly = Vec::new();
+        self._write_command(command, &mut assembly);
+        self._write_debug_comments(std::slice::from_ref(command), &mut assembly, 0);
+        assembly
+    }
+
+    fn _write_command(&mut self, command: &Command, assembly: &mut Vec<Instruction>) {
+        match command {
+            Command::Add => self.write_add(assembly),
+            Command::Sub => self.write_sub(assembly),
+            Command::Neg => self.write_neg(assembly),
+            Command::Eq => self.write_eq(assembly),
+            Command::Gt => self.write_gt(assembly),
+            Command::Lt => self.write_lt(assembly),
+            Command::And => self.write_and(assembly),
+            Command::Or => self.write_or(assembly),
+            Command::Not => self.write_not(assembly),
+            Command::Push(segment, address) => self.write_push(segment, *address, assembly),
+            Command::Pop(segment, address) => self.write_pop(segment, *address, assembly),
+            Command::Label(value) => self.write_label(value, assembly),
+            Command::Goto(value) => self.write_goto(value, assembly),
+            Command::IfGoto(value) => self.write_ifgoto(value, assembly),
+            Command::Function(name, nargs) => self.write_function(name, *nargs, assembly),
+            Command::Call(name, nargs) => match Intrinsic::find(name, *nargs) {
+                Some(intrinsic) if self.intrinsics && !self.defined.contains(name) => {
+                    self._write_intrinsic(intrinsic, assembly)
+                }
+                _ => self.write_call(name, *nargs, assembly),
+            },
+            Command::Return => self.write_return(assembly),
+            _ => self._extend(assembly, [Instruction::comment("Not implemented yet")]),
+        }
+    }
+
+    /// Writes the command(s) at the front of `commands`, returning how many were consumed.
+    /// Runs of commands matching a tile are lowered together; anything else is written alone.
+    pub fn write_next(&mut self, commands: &[Command]) -> (usize, Vec<Instruction>) {
+        let mut assembly = Vec::new();
+        let consumed = self._write_next(commands, &mut assembly);
+        (consumed, assembly)
+    }
 
-        format!("{}\n{}", debug_comment, assembly)
+    fn _write_next(&mut self, commands: &[Command], assembly: &mut Vec<Instruction>) -> usize {
+        let start = assembly.len();
+        let consumed = self._lower_next(commands, assembly);
+        if consumed == 0 {
+            return 0;
+        }
+        let commands = &commands[..consumed];
+        self._write_debug_comments(commands, assembly, start);
+
+        // checks go after the leading comments, by writing them at the end and rotating them
+        // into place
+        let header = start
+            + assembly[start..]
+                .iter()
+                .take_while(|instruction| matches!(instruction, Instruction::Comment(_)))
+                .count();
+        if self.checks.heap {
+            let end = assembly.len();
+            self._write_heap_checks(commands, assembly);
+            let checks = assembly.len() - end;
+            assembly[header..].rotate_right(checks);
+        }
+        if self.checks.stack {
+            let end = assembly.len();
+            self._write_stack_check(commands, assembly);
+            // a jump could skip a check at the end, so such blocks are checked first
+            if checks::ends_in_jump(commands) {
+                let check = assembly.len() - end;
+                assembly[header..].rotate_right(check);
+            }
+        }
+        consumed
+    }
+
+    fn _lower_next(&mut self, commands: &[Command], assembly: &mut Vec<Instruction>) -> usize {
+        for tile in tiles::TILES {
+            if let Some(consumed) = tile(self, commands, assembly) {
+                return consumed;
+            }
+        }
+
+        match commands {
+            [command, ..] => {
+                self._write_command(command, assembly);
+                1
+            }
+            [] => 0,
+        }
     }
 
-    pub fn write_add(&self) -> String {
-        format!("{}\n{}", self._binary_op().join("\n"), "M=D+M")
+    pub fn write_add(&self, assembly: &mut Vec<Instruction>) {
+        self._binary_op(assembly);
+        assembly.push(Instruction::set(Dest::M, Comp::DPlusM));
     }
 
-    pub fn write_sub(&self) -> String {
-        format!("{}\n{}", self._binary_op().join("\n"), "M=M-D")
+    pub fn write_sub(&self, assembly: &mut Vec<Instruction>) {
+        self._binary_op(assembly);
+        assembly.push(Instruction::set(Dest::M, Comp::MMinusD));
     }
 
-    pub fn write_neg(&self) -> String {
-        format!("{}\n{}", self._unary_op().join("\n"), "M=-D")
+    pub fn write_neg(&self, assembly: &mut Vec<Instruction>) {
+        self._unary_op(assembly);
+        assembly.push(Instruction::set(Dest::M, Comp::NegD));
     }
 
-    pub fn write_eq(&mut self) -> String {
-        self._write_comparison("JEQ")
+    pub fn write_eq(&mut self, assembly: &mut Vec<Instruction>) {
+        self._write_comparison(Jump::JEQ, assembly)
     }
 
-    pub fn write_lt(&mut self) -> String {
-        self._write_comparison("JLT")
+    pub fn write_lt(&mut self, assembly: &mut Vec<Instruction>) {
+        self._write_comparison(Jump::JLT, assembly)
     }
 
-    pub fn write_gt(&mut self) -> String {
-        self._write_comparison("JGT")
+    pub fn write_gt(&mut self, assembly: &mut Vec<Instruction>) {
+        self._write_comparison(Jump::JGT, assembly)
     }
 
-    pub fn write_and(&self) -> String {
-        format!("{}\n{}", self._binary_op().join("\n"), "M=D&M")
+    pub fn write_and(&self, assembly: &mut Vec<Instruction>) {
+        self._binary_op(assembly);
+        assembly.push(Instruction::set(Dest::M, Comp::DAndM));
     }
 
-    pub fn write_or(&self) -> String {
-        format!("{}\n{}", self._binary_op().join("\n"), "M=D|M")
+    pub fn write_or(&self, assembly: &mut Vec<Instruction>) {
+        self._binary_op(assembly);
+        assembly.push(Instruction::set(Dest::M, Comp::DOrM));
     }
 
-    pub fn write_not(&self) -> String {
-        format!("{}\n{}", self._unary_op().join("\n"), "M=!D")
+    pub fn write_not(&self, assembly: &mut Vec<Instruction>) {
+        self._unary_op(assembly);
+        assembly.push(Instruction::set(Dest::M, Comp::NotD));
     }
 
-    pub fn write_push(&self, segment: &MemorySegment, argument: u16) -> String {
+    pub fn write_push(
+        &self,
+        segment: &MemorySegment,
+        argument: u16,
+        assembly: &mut Vec<Instruction>,
+    ) {
         if *segment == MemorySegment::Constant {
-            format!(
-                "{}\n{}",
+            self._extend(
+                assembly,
                 [
-                    &format!("@{}", argument), // load the constant into A
-                    "D=A",                     // move it to D
-                ]
-                .join("\n"),
-                self._push().join("\n"), // push D
-            )
+                    Instruction::comment("load the constant into A"),
+                    Instruction::constant(argument),
+                    Instruction::comment("move it to D"),
+                    Instruction::set(Dest::D, Comp::A),
+                ],
+            );
         } else if *segment == MemorySegment::Static {
-            format!(
-                "@{}.{}\nD=M\n{}",
-                self.context.file,
-                argument,
-                self._push().join("\n")
-            )
+            assembly.extend([
+                Instruction::at(format!("{}.{}", self.context.file, argument)),
+                Instruction::set(Dest::D, Comp::M),
+            ]);
         } else {
-            format!(
-                "{}\n{}",
+            self._extend(
+                assembly,
+                [Instruction::comment("load the base address into D")],
+            );
+            self._get_base_address(segment, assembly);
+            self._extend(
+                assembly,
                 [
-                    // load the base address into D
-                    &self._get_base_address(segment),
-                    "D=A",
-                    // load the index into A
-                    &format!("@{}", argument),
-                    // index into segment with A
-                    "A=D+A",
-                    // load value into D
-                    "D=M"
-                ]
-                .join("\n"),
-                self._push().join("\n")
-            )
+                    Instruction::set(Dest::D, Comp::A),
+                    Instruction::comment("load the index into A"),
+                    Instruction::constant(argument),
+                    Instruction::comment("index into segment with A"),
+                    Instruction::set(Dest::A, Comp::DPlusA),
+                    Instruction::comment("load value into D"),
+                    Instruction::set(Dest::D, Comp::M),
+                ],
+            );
         }
+        self._push(assembly);
     }
 
-    pub fn write_pop(&self, segment: &MemorySegment, argument: u16) -> String {
+    pub fn write_pop(
+        &self,
+        segment: &MemorySegment,
+        argument: u16,
+        assembly: &mut Vec<Instruction>,
+    ) {
         if *segment == MemorySegment::Static {
-            format!(
-                "D=0\n@{}.{}\n{}",
-                self.context.file,
-                argument,
-                self._pop().join("\n")
-            )
+            assembly.extend([
+                Instruction::set(Dest::D, Comp::Zero),
+                Instruction::at(format!("{}.{}", self.context.file, argument)),
+            ]);
         } else {
-            let get_base_address = self._get_base_address(segment);
-
-            format!(
-                "{}\n{}",
+            self._extend(
+                assembly,
+                [Instruction::comment("load the base address into D")],
+            );
+            self._get_base_address(segment, assembly);
+            self._extend(
+                assembly,
                 [
-                    // load the base address into D
-                    &get_base_address,
-                    "D=A",
-                    // load the index into A
-                    &format!("@{}", argument)
-                ]
-                .join("\n"),
-                self._pop().join("\n"), // pop stack into D+A
-            )
+                    Instruction::set(Dest::D, Comp::A),
+                    Instruction::comment("load the index into A"),
+                    Instruction::constant(argument),
+                ],
+            );
         }
+        self._pop(assembly);
+    }
+
+    pub fn write_label(&self, label: &str, assembly: &mut Vec<Instruction>) {
+        assembly.push(Instruction::label(self._scoped_label(label)));
     }
 
-    pub fn write_label(&self, label: &str) -> String {
-        format!("({}${})", self.context, label)
+    pub fn write_goto(&self, label: &str, assembly: &mut Vec<Instruction>) {
+        assembly.extend([
+            Instruction::at(self._scoped_label(label)),
+            Instruction::jump(Comp::Zero, Jump::JMP),
+        ]);
     }
 
-    pub fn write_goto(&self, label: &str) -> String {
-        format!("@{}${}\n0;JMP", self.context, label)
+    pub fn write_ifgoto(&self, label: &str, assembly: &mut Vec<Instruction>) {
+        self._extend(
+            assembly,
+            [
+                Instruction::comment("pop the stack into D"),
+                Instruction::at("SP"),
+                Instruction::set(Dest::AM, Comp::MMinusOne),
+                Instruction::set(Dest::D, Comp::M),
+                Instruction::comment("load the label into A"),
+                Instruction::at(self._scoped_label(label)),
+                Instruction::comment("jump there if D != 0"),
+                Instruction::jump(Comp::D, Jump::JNE),
+            ],
+        );
     }
 
-    pub fn write_ifgoto(&self, label: &str) -> String {
-        [
-            // pop the stack into D
-            "@SP",
-            "AM=M-1",
-            "D=M",
-            // load the label into A
-            &format!("@{}${}", self.context, label),
-            // jump there if D != 0
-            "D;JNE",
-        ]
-        .join("\n")
+    /// Pops two values and jumps to `label` if the comparison holds (or fails, if `negate`),
+    /// without materializing the boolean on the stack
+    pub fn write_compare_branch(
+        &self,
+        comparison: &Command,
+        label: &str,
+        negate: bool,
+        assembly: &mut Vec<Instruction>,
+    ) {
+        let jump_condition = match (comparison, negate) {
+            (Command::Eq, false) => Jump::JEQ,
+            (Command::Eq, true) => Jump::JNE,
+            (Command::Gt, false) => Jump::JGT,
+            (Command::Gt, true) => Jump::JLE,
+            (Command::Lt, false) => Jump::JLT,
+            (Command::Lt, true) => Jump::JGE,
+            _ => panic!("Invalid command for compare-and-branch: {}", comparison),
+        };
+        self._extend(
+            assembly,
+            [
+                Instruction::comment("point to stack pointer"),
+                Instruction::at("SP"),
+                Instruction::comment("pop the top of the stack"),
+                Instruction::set(Dest::M, Comp::MMinusOne),
+                Instruction::comment("pop and point to the element below it"),
+                Instruction::set(Dest::AM, Comp::MMinusOne),
+                Instruction::comment("load the lower element"),
+                Instruction::set(Dest::D, Comp::M),
+                Instruction::comment("point to the top element"),
+                Instruction::set(Dest::A, Comp::APlusOne),
+                Instruction::comment("subtract top from bottom"),
+                Instruction::set(Dest::D, Comp::DMinusM),
+                Instruction::comment("load the label into A"),
+                Instruction::at(self._scoped_label(label)),
+                Instruction::comment("jump there based on the jump_condition"),
+                Instruction::jump(Comp::D, jump_condition),
+            ],
+        );
     }
 
-    pub fn write_function(&mut self, name: &str, nlocals: u16) -> String {
+    pub fn write_function(&mut self, name: &str, nlocals: u16, assembly: &mut Vec<Instruction>) {
         self._set_function_context(name.to_string());
-        format!(
-            "({}){}",
-            self.context,
-            format!("\nD=0\n{}", self._push().join("\n")).repeat(nlocals as usize)
-        )
+        assembly.push(Instruction::label(self.context.to_string()));
+        if self.profiling {
+            self._write_counter(assembly);
+        }
+        for _ in 0..nlocals {
+            assembly.push(Instruction::set(Dest::D, Comp::Zero));
+            self._push(assembly);
+        }
     }
 
-    pub fn write_call(&mut self, name: &str, nargs: u16) -> String {
-        let call_label = format!("__RET_{}", self._next_label_id());
-        [
-            // push return-address
-            &format!("@{}\nD=A", call_label),
-            &self._push().join("\n"),
-            // store LCL, ARG, THIS, and THAT on stack
-            &self._push_segment("LCL"),
-            &self._push_segment("ARG"),
-            &self._push_segment("THIS"),
-            &self._push_segment("THAT"),
-            // reposition ARG
-            &format!("@{}", nargs + 5),
-            "D=A",
-            "@SP",
-            "D=M-D",
-            "@ARG",
-            "M=D",
-            // reposition LCL
-            "@SP",
-            "D=M",
-            "@LCL",
-            "M=D",
-            // transfer control
-            &format!("@{}\n0;JMP", name),
-            // provide return address
-            &format!("({})", call_label),
-        ]
-        .join("\n")
-    }
-
-    pub fn write_return(&mut self) -> String {
-        [
-            // stash stack frame pointer in a general-purpose register
-            "@LCL",
-            "D=M",
-            "@R14",
-            "M=D",
-            // store return address in another register
-            "@5",
-            "A=D-A",
-            "D=M",
-            "@R15",
-            "M=D",
-            // pop return value into position (same as base of argument segment)
-            "@SP",
-            "AM=M-1",
-            "D=M", // D set to return value
-            "@ARG",
-            "A=M",
-            "M=D", // ARG[0] = D
-            // restore SP -- just above return value
-            "D=A+1",
-            "@SP",
-            "M=D",
-            // restore THAT, THIS, ARG, LCL
-            &self._restore_segment("THAT", 1),
-            &self._restore_segment("THIS", 2),
-            &self._restore_segment("ARG", 3),
-            &self._restore_segment("LCL", 4),
-            // relinquish control
-            "@R15",
-            "A=M",
-            "0;JMP",
-        ]
-        .join("\n")
-    }
-
-    pub fn _push_segment(&self, segment_pointer: &str) -> String {
-        [
-            &format!("@{}", segment_pointer),
-            "D=M",
-            &self._push().join("\n"),
-        ]
-        .join("\n")
-    }
-
-    pub fn _restore_segment(&self, segment_pointer: &str, frame_offset: u16) -> String {
-        [
-            // load frame top into D
-            "@R14",
-            "D=M",
-            // subtract offset
-            &format!("@{}", frame_offset),
-            "A=D-A",
-            // load contents
-            "D=M",
-            // restore into segment pointer
-            &format!("@{}", segment_pointer),
-            "M=D",
-        ]
-        .join("\n")
-    }
-
-    fn _write_comparison(&mut self, jump_condition: &str) -> String {
+    pub fn write_call(&mut self, name: &str, nargs: u16, assembly: &mut Vec<Instruction>) {
+        let call_label = format!("{}$ret.{}", self.context, self.return_counter);
+        self.return_counter += 1;
+        self._extend(
+            assembly,
+            [
+                Instruction::comment("push return-address"),
+                Instruction::at(call_label.clone()),
+                Instruction::set(Dest::D, Comp::A),
+            ],
+        );
+        self._push(assembly);
+        self._extend(
+            assembly,
+            [Instruction::comment(
+                "store LCL, ARG, THIS, and THAT on stack",
+            )],
+        );
+        for pointer in ["LCL", "ARG", "THIS", "THAT"] {
+            self._push_segment(pointer, assembly);
+        }
+        self._extend(
+            assembly,
+            [
+                Instruction::comment("reposition ARG"),
+                Instruction::constant(nargs + 5),
+                Instruction::set(Dest::D, Comp::A),
+                Instruction::at("SP"),
+                Instruction::set(Dest::D, Comp::MMinusD),
+                Instruction::at("ARG"),
+                Instruction::set(Dest::M, Comp::D),
+                Instruction::comment("reposition LCL"),
+                Instruction::at("SP"),
+                Instruction::set(Dest::D, Comp::M),
+                Instruction::at("LCL"),
+                Instruction::set(Dest::M, Comp::D),
+                Instruction::comment("transfer control"),
+                Instruction::at(mangle(name)),
+                Instruction::jump(Comp::Zero, Jump::JMP),
+                Instruction::comment("provide return address"),
+                Instruction::label(call_label),
+            ],
+        );
+    }
+
+    pub fn write_return(&mut self, assembly: &mut Vec<Instruction>) {
+        self._extend(
+            assembly,
+            [
+                Instruction::comment("stash stack frame pointer in a general-purpose register"),
+                Instruction::at("LCL"),
+                Instruction::set(Dest::D, Comp::M),
+                Instruction::at("R14"),
+                Instruction::set(Dest::M, Comp::D),
+                Instruction::comment("store return address in another register"),
+                Instruction::constant(5),
+                Instruction::set(Dest::A, Comp::DMinusA),
+                Instruction::set(Dest::D, Comp::M),
+                Instruction::at("R15"),
+                Instruction::set(Dest::M, Comp::D),
+                Instruction::comment(
+                    "pop return value into position (same as base of argument segment)",
+                ),
+                Instruction::at("SP"),
+                Instruction::set(Dest::AM, Comp::MMinusOne),
+                Instruction::comment("D set to return value"),
+                Instruction::set(Dest::D, Comp::M),
+                Instruction::at("ARG"),
+                Instruction::set(Dest::A, Comp::M),
+                Instruction::comment("ARG[0] = D"),
+                Instruction::set(Dest::M, Comp::D),
+                Instruction::comment("restore SP -- just above return value"),
+                Instruction::set(Dest::D, Comp::APlusOne),
+                Instruction::at("SP"),
+                Instruction::set(Dest::M, Comp::D),
+                Instruction::comment("restore THAT, THIS, ARG, LCL"),
+            ],
+        );
+        for (pointer, frame_offset) in [("THAT", 1), ("THIS", 2), ("ARG", 3), ("LCL", 4)] {
+            self._restore_segment(pointer, frame_offset, assembly);
+        }
+        self._extend(
+            assembly,
+            [
+                Instruction::comment("relinquish control"),
+                Instruction::at("R15"),
+                Instruction::set(Dest::A, Comp::M),
+                Instruction::jump(Comp::Zero, Jump::JMP),
+            ],
+        );
+    }
+
+    pub fn _push_segment(&self, segment_pointer: &'static str, assembly: &mut Vec<Instruction>) {
+        assembly.extend([
+            Instruction::at(segment_pointer),
+            Instruction::set(Dest::D, Comp::M),
+        ]);
+        self._push(assembly);
+    }
+
+    pub fn _restore_segment(
+        &self,
+        segment_pointer: &'static str,
+        frame_offset: u16,
+        assembly: &mut Vec<Instruction>,
+    ) {
+        self._extend(
+            assembly,
+            [
+                Instruction::comment("load frame top into D"),
+                Instruction::at("R14"),
+                Instruction::set(Dest::D, Comp::M),
+                Instruction::comment("subtract offset"),
+                Instruction::constant(frame_offset),
+                Instruction::set(Dest::A, Comp::DMinusA),
+                Instruction::comment("load contents"),
+                Instruction::set(Dest::D, Comp::M),
+                Instruction::comment("restore into segment pointer"),
+                Instruction::at(segment_pointer),
+                Instruction::set(Dest::M, Comp::D),
+            ],
+        );
+    }
+
+    /// Sets a pointer to any 16-bit value, including those too wide for an A-instruction
+    fn _write_pointer(&self, pointer: &'static str, value: u16, assembly: &mut Vec<Instruction>) {
+        if value <= MAX_CONSTANT {
+            assembly.extend([
+                Instruction::constant(value),
+                Instruction::set(Dest::D, Comp::A),
+            ]);
+        } else {
+            assembly.extend([
+                Instruction::constant(!value),
+                Instruction::set(Dest::D, Comp::NotA),
+            ]);
+        }
+        assembly.extend([Instruction::at(pointer), Instruction::set(Dest::M, Comp::D)]);
+    }
+
+    /// Loops forever once the program is done
+    fn _write_halt(&self, assembly: &mut Vec<Instruction>) {
+        self._extend(
+            assembly,
+            [
+                Instruction::comment("halt"),
+                Instruction::label("$halt"),
+                Instruction::at("$halt"),
+                Instruction::jump(Comp::Zero, Jump::JMP),
+            ],
+        );
+    }
+
+    fn _write_comparison(&mut self, jump_condition: Jump, assembly: &mut Vec<Instruction>) {
         let label_id = self._next_label_id();
-        let true_label = format!("TRUE.{}", label_id);
-        let out_label = format!("OUT.{}", label_id);
-        format!(
-            "{}\n{}",
-            self._binary_op().join("\n"),
+        let true_label = format!("$TRUE.{}", label_id);
+        let out_label = format!("$OUT.{}", label_id);
+        self._binary_op(assembly);
+        self._extend(
+            assembly,
             [
-                "D=M-D",                          // subtract top from bottom
-                &format!("@{}", true_label),      // possibly jump to TRUE
-                &format!("D;{}", jump_condition), // based on the jump_condition
-                "D=0",                            // if not, result is false
-                &format!("@{}", out_label),       // so jump to out_label
-                "0;JMP",                          // to write to the stack
-                &format!("({})", true_label),     // if we jumped here,
-                "D=-1",                           // result is true (0xFFFF)
-                &format!("({})", out_label),      // ready to produce output
-                "@SP",                            // point to stack pointer
-                "A=M-1",                          // point to the top of the stack
-                "M=D"                             // write result to stack
-            ]
-            .join("\n")
-        )
+                Instruction::comment("subtract top from bottom"),
+                Instruction::set(Dest::D, Comp::MMinusD),
+                Instruction::comment("possibly jump to TRUE"),
+                Instruction::at(true_label.clone()),
+                Instruction::comment("based on the jump_condition"),
+                Instruction::jump(Comp::D, jump_condition),
+                Instruction::comment("if not, result is false"),
+                Instruction::set(Dest::D, Comp::Zero),
+                Instruction::comment("so jump to out_label"),
+                Instruction::at(out_label.clone()),
+                Instruction::comment("to write to the stack"),
+                Instruction::jump(Comp::Zero, Jump::JMP),
+                Instruction::comment("if we jumped here,"),
+                Instruction::label(true_label),
+                Instruction::comment("result is true (0xFFFF)"),
+                Instruction::set(Dest::D, Comp::MinusOne),
+                Instruction::comment("ready to produce output"),
+                Instruction::label(out_label),
+                Instruction::comment("point to stack pointer"),
+                Instruction::at("SP"),
+                Instruction::comment("point to the top of the stack"),
+                Instruction::set(Dest::A, Comp::MMinusOne),
+                Instruction::comment("write result to stack"),
+                Instruction::set(Dest::M, Comp::D),
+            ],
+        );
+    }
+
+    /// Puts the commands a block implements in front of it, the block starting at `start`,
+    /// as the comment level allows
+    fn _write_debug_comments(
+        &self,
+        commands: &[Command],
+        assembly: &mut Vec<Instruction>,
+        start: usize,
+    ) {
+        if self.comments == CommentLevel::None {
+            return;
+        }
+
+        let end = assembly.len();
+        if self.comments == CommentLevel::Verbose {
+            assembly.push(Instruction::comment(format!(
+                "{}:{}",
+                self.context.source, self.context.line
+            )));
+        }
+        assembly.extend(
+            commands
+                .iter()
+                .map(|command| Instruction::comment(command.to_string())),
+        );
+        let comments = assembly.len() - end;
+        assembly[start..].rotate_right(comments);
+    }
+
+    /// Appends a template's instructions, leaving out its own comments unless verbose output
+    /// was asked for, so they're never kept only to be stripped
+    fn _extend(
+        &self,
+        assembly: &mut Vec<Instruction>,
+        instructions: impl IntoIterator<Item = Instruction>,
+    ) {
+        let verbose = self.comments == CommentLevel::Verbose;
+        assembly.extend(
+            instructions
+                .into_iter()
+                .filter(|instruction| verbose || !matches!(instruction, Instruction::Comment(_))),
+        );
     }
 
     /// Map each segment to its 'well-known' address -- which may contain a pointer to its base
-    fn _get_segment_well_known_addr(&self, segment: &MemorySegment) -> String {
+    fn _get_segment_well_known_addr(&self, segment: &MemorySegment) -> Instruction {
         match segment {
-            MemorySegment::Local => "@LCL",
-            MemorySegment::Argument => "@ARG",
-            MemorySegment::This => "@THIS",
-            MemorySegment::That => "@THAT",
-            MemorySegment::Temp => "@5",
-            MemorySegment::Pointer => "@3",
+            MemorySegment::Local => Instruction::at("LCL"),
+            MemorySegment::Argument => Instruction::at("ARG"),
+            MemorySegment::This => Instruction::at("THIS"),
+            MemorySegment::That => Instruction::at("THAT"),
+            MemorySegment::Temp | MemorySegment::Pointer => {
+                Instruction::constant(self._fixed_segment_base(segment))
+            }
             _ => panic!("Invalid segment for address calculation"),
         }
-        .to_string()
     }
 
-    /// Loads the base address of a segment into A
-    fn _get_base_address(&self, segment: &MemorySegment) -> String {
-        let segment_well_known_addr = self._get_segment_well_known_addr(segment);
-        let is_pointer = self._is_pointed_segment(segment);
+    /// Base address of the segments that live at a fixed location in RAM
+    fn _fixed_segment_base(&self, segment: &MemorySegment) -> u16 {
+        match segment {
+            MemorySegment::Temp => self.memory_map.temp,
+            MemorySegment::Pointer => self.memory_map.pointer,
+            _ => panic!("Segment {} is not at a fixed address", segment),
+        }
+    }
 
-        if is_pointer {
-            // chase pointer
-            format!("{}\nA=M", segment_well_known_addr)
+    /// Loads the address of `segment[index]` into A, clobbering D if `_address_clobbers_d`
+    fn _segment_address(
+        &self,
+        segment: &MemorySegment,
+        index: u16,
+        assembly: &mut Vec<Instruction>,
+    ) {
+        match segment {
+            MemorySegment::Static => {
+                assembly.push(Instruction::at(format!("{}.{}", self.context.file, index)))
+            }
+            MemorySegment::Temp | MemorySegment::Pointer => assembly.push(Instruction::constant(
+                self._fixed_segment_base(segment) + index,
+            )),
+            _ => match index {
+                // step up from the base when that's shorter than adding the index
+                0 => self._get_base_address(segment, assembly),
+                1 => assembly.extend([
+                    self._get_segment_well_known_addr(segment),
+                    Instruction::set(Dest::A, Comp::MPlusOne),
+                ]),
+                2 => assembly.extend([
+                    self._get_segment_well_known_addr(segment),
+                    Instruction::set(Dest::A, Comp::MPlusOne),
+                    Instruction::set(Dest::A, Comp::APlusOne),
+                ]),
+                _ => assembly.extend([
+                    self._get_segment_well_known_addr(segment),
+                    Instruction::set(Dest::D, Comp::M),
+                    Instruction::constant(index),
+                    Instruction::set(Dest::A, Comp::DPlusA),
+                ]),
+            },
+        }
+    }
+
+    fn _address_clobbers_d(&self, segment: &MemorySegment, index: u16) -> bool {
+        self._is_pointed_segment(segment) && index > 2
+    }
+
+    /// Loads the value of `segment[index]` into D
+    fn _load_value(&self, segment: &MemorySegment, index: u16, assembly: &mut Vec<Instruction>) {
+        if *segment == MemorySegment::Constant {
+            assembly.extend([
+                Instruction::constant(index),
+                Instruction::set(Dest::D, Comp::A),
+            ]);
         } else {
-            segment_well_known_addr
+            self._segment_address(segment, index, assembly);
+            assembly.push(Instruction::set(Dest::D, Comp::M));
+        }
+    }
+
+    /// Loads the base address of a segment into A
+    fn _get_base_address(&self, segment: &MemorySegment, assembly: &mut Vec<Instruction>) {
+        assembly.push(self._get_segment_well_known_addr(segment));
+        if self._is_pointed_segment(segment) {
+            // chase pointer
+            assembly.push(Instruction::set(Dest::A, Comp::M));
         }
     }
 
@@ -367,39 +859,100 @@ impl CodeWriter {
     }
 
     /// Pushes D onto the top of the stack
-    fn _push(&self) -> [&str; 5] {
-        [
-            "@SP",   // point to the stack pointer
-            "A=M",   // load the stack pointer into A
-            "M=D",   // write the value onto the stack
-            "@SP",   // increment the stack pointer
-            "M=M+1", //
-        ]
+    fn _push(&self, assembly: &mut Vec<Instruction>) {
+        self._extend(
+            assembly,
+            [
+                Instruction::comment("point to the stack pointer"),
+                Instruction::at("SP"),
+                Instruction::comment("load the stack pointer into A"),
+                Instruction::set(Dest::A, Comp::M),
+                Instruction::comment("write the value onto the stack"),
+                Instruction::set(Dest::M, Comp::D),
+                Instruction::comment("increment the stack pointer"),
+                Instruction::at("SP"),
+                Instruction::set(Dest::M, Comp::MPlusOne),
+            ],
+        );
     }
 
     /// Pops top of stack into D+A, via R13
-    fn _pop(&self) -> [&str; 9] {
-        [
-            // store D+A in general-purpose register
-            "D=D+A", "@R13", "M=D", // pop stack into D and decrement
-            "@SP", "AM=M-1", "D=M", // store D into *R13
-            "@R13", "A=M", "M=D",
-        ]
+    fn _pop(&self, assembly: &mut Vec<Instruction>) {
+        self._extend(
+            assembly,
+            [
+                Instruction::comment("store D+A in general-purpose register"),
+                Instruction::set(Dest::D, Comp::DPlusA),
+                Instruction::at("R13"),
+                Instruction::set(Dest::M, Comp::D),
+                Instruction::comment("pop stack into D and decrement"),
+                Instruction::at("SP"),
+                Instruction::set(Dest::AM, Comp::MMinusOne),
+                Instruction::set(Dest::D, Comp::M),
+                Instruction::comment("store D into *R13"),
+                Instruction::at("R13"),
+                Instruction::set(Dest::A, Comp::M),
+                Instruction::set(Dest::M, Comp::D),
+            ],
+        );
     }
 
     /// Loads top of the stack into D and points A at next stack element
-    fn _binary_op(&self) -> [&str; 4] {
-        [
-            "@SP",    // point to stack pointer
-            "AM=M-1", // decrement stack pointer and load it
-            "D=M",    // follow stack pointer
-            "A=A-1",  // point one below top of stack
-        ]
+    fn _binary_op(&self, assembly: &mut Vec<Instruction>) {
+        self._extend(
+            assembly,
+            [
+                Instruction::comment("point to stack pointer"),
+                Instruction::at("SP"),
+                Instruction::comment("decrement stack pointer and load it"),
+                Instruction::set(Dest::AM, Comp::MMinusOne),
+                Instruction::comment("follow stack pointer"),
+                Instruction::set(Dest::D, Comp::M),
+                Instruction::comment("point one below top of stack"),
+                Instruction::set(Dest::A, Comp::AMinusOne),
+            ],
+        );
     }
 
     /// Loads the top of the stack into D
-    fn _unary_op(&self) -> [&str; 3] {
-        ["@SP", "A=M-1", "D=M"]
+    fn _unary_op(&self, assembly: &mut Vec<Instruction>) {
+        assembly.extend([
+            Instruction::at("SP"),
+            Instruction::set(Dest::A, Comp::MMinusOne),
+            Instruction::set(Dest::D, Comp::M),
+        ]);
+    }
+
+    /// A user label, namespaced by the function (or file) it appears in
+    fn _scoped_label(&self, label: &str) -> String {
+        format!("{}${}", self.context, mangle_label(label))
+    }
+
+    /// Writes a block into the reused instruction buffer, then prints it. Instructions only
+    /// become text here, on their way out, and are checked as they go.
+    fn _write_block<T>(
+        &mut self,
+        out: &mut dyn fmt::Write,
+        write: impl FnOnce(&mut Self, &mut Vec<Instruction>) -> T,
+    ) -> Result<T, fmt::Error> {
+        let mut assembly = std::mem::take(&mut self.assembly);
+        assembly.clear();
+        let written = write(self, &mut assembly);
+        let printed = assembly.iter().try_for_each(|instruction| {
+            self.verifier.check(instruction);
+            writeln!(out, "{}", instruction)
+        });
+        self.assembly = assembly;
+        printed.map(|_| written)
+    }
+
+    /// Identifies the function being written in traps and profiles; 0 is code outside any
+    fn _function_id(&self) -> usize {
+        if self.context.function.is_empty() {
+            0
+        } else {
+            self.functions.len()
+        }
     }
 
     /// Generate a unique label ID for jump operations
@@ -409,3 +962,311 @@ impl CodeWriter {
         id
     }
 }
+
+impl Backend for CodeWriter {
+    fn write_bootstrap(&mut self, out: &mut dyn fmt::Write) -> fmt::Result {
+        self._write_block(out, |codewriter, assembly| {
+            codewriter._write_bootstrap(assembly);
+            codewriter.sizes.add("(bootstrap)", "(bootstrap)", assembly);
+        })
+    }
+
+    fn entry_point(&self) -> &str {
+        &self.bootstrap.entry
+    }
+
+    fn set_defined_functions(&mut self, functions: &HashSet<String>) {
+        self.defined = functions.clone();
+    }
+
+    fn set_file_context(&mut self, module: String) {
+        CodeWriter::set_file_context(self, module)
+    }
+
+    fn set_source_context(&mut self, source: &str, line: usize) {
+        CodeWriter::set_source_context(self, source, line)
+    }
+
+    fn write_next(
+        &mut self,
+        commands: &[Command],
+        out: &mut dyn fmt::Write,
+    ) -> Result<usize, fmt::Error> {
+        self._write_block(out, |codewriter, assembly| {
+            let consumed = codewriter._write_next(commands, assembly);
+            if let Some(command) = commands.first() {
+                let function = codewriter.context.counted_as();
+                codewriter
+                    .sizes
+                    .add(&function, size::command_kind(command), assembly);
+            }
+            consumed
+        })
+    }
+
+    /// A halt for programs without a bootstrap, the call counters' table, the intrinsics'
+    /// routines and any traps the runtime checks jump to
+    fn write_epilogue(&mut self, out: &mut dyn fmt::Write) -> fmt::Result {
+        self._write_block(out, |codewriter, assembly| {
+            if codewriter.bootstrap.halt && !codewriter.bootstrapped {
+                codewriter._write_halt(assembly);
+            }
+            codewriter._write_counter_table(assembly);
+            codewriter._write_routines(assembly);
+            codewriter._write_traps(assembly);
+            codewriter.sizes.add("(runtime)", "(runtime)", assembly);
+        })
+    }
+
+    /// Labels and the program's size are only checked once the whole program exists
+    fn check(&mut self) -> Result<(), String> {
+        std::mem::take(&mut self.verifier).finish()?;
+        self._check_counters()?;
+        self._check_size()
+    }
+}
+
+/// User identifiers have every `$` doubled, so a lone `$` only ever starts a label the
+/// translator generates or separates a function (or file) from one of its labels
+fn mangle(identifier: &str) -> String {
+    identifier.replace('$', "$$")
+}
+
+/// A label starting with `$` would run into the separator, and within a function `ret.{i}`
+/// names its return points, so such labels are set apart by a `.$`: a lone `$` that
+/// doubling never produces
+fn mangle_label(label: &str) -> String {
+    let is_return_label = label
+        .strip_prefix("ret.")
+        .is_some_and(|i| !i.is_empty() && i.chars().all(|c| c.is_ascii_digit()));
+    if is_return_label || label.starts_with('$') {
+        format!(".${}", mangle(label))
+    } else {
+        mangle(label)
+    }
+}
+
+#[cfg(test)]
+mod namespace_tests {
+    use super::*;
+    use crate::hack;
+
+    /// What a template writes on its own
+    fn written(write: impl FnOnce(&mut Vec<Instruction>)) -> Vec<Instruction> {
+        let mut assembly = Vec::new();
+        write(&mut assembly);
+        assembly
+    }
+
+    #[test]
+    fn test_generated_labels_are_reserved() {
+        let mut codewriter = CodeWriter::new();
+        let comparison = written(|assembly| codewriter.write_eq(assembly));
+
+        assert!(comparison.contains(&Instruction::label("$TRUE.1")));
+        assert!(comparison.contains(&Instruction::label("$OUT.1")));
+        assert!(codewriter
+            .write_bootstrap()
+            .contains(&Instruction::label("$bootstrap$ret.1")));
+    }
+
+    #[test]
+    fn test_return_labels_counted_per_function() {
+        let mut codewriter = CodeWriter::new();
+        written(|assembly| codewriter.write_function("Main.main", 0, assembly));
+        assert_eq!(
+            written(|assembly| codewriter.write_call("Main.f", 0, assembly)).last(),
+            Some(&Instruction::label("Main.main$ret.1"))
+        );
+        assert_eq!(
+            written(|assembly| codewriter.write_call("Main.f", 0, assembly)).last(),
+            Some(&Instruction::label("Main.main$ret.2"))
+        );
+
+        written(|assembly| codewriter.write_function("Main.f", 0, assembly));
+        assert_eq!(
+            written(|assembly| codewriter.write_call("Main.g", 0, assembly)).last(),
+            Some(&Instruction::label("Main.f$ret.1"))
+        );
+        assert_eq!(
+            written(|assembly| codewriter.write_label("ret.1", assembly)),
+            [Instruction::label("Main.f$.$ret.1")]
+        );
+        assert_eq!(
+            written(|assembly| codewriter.write_label("ret.x", assembly)),
+            [Instruction::label("Main.f$ret.x")]
+        );
+    }
+
+    #[test]
+    fn test_user_identifiers_are_mangled() {
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_file_context("$Lib".to_string());
+
+        assert_eq!(
+            written(|assembly| codewriter.write_function("$TRUE.1", 0, assembly)),
+            [Instruction::label("$$TRUE.1")]
+        );
+        let call = written(|assembly| codewriter.write_call("$OUT.1", 0, assembly));
+        assert!(hack::print(&call).contains("@$$OUT.1\n0;JMP"));
+        assert_eq!(
+            written(|assembly| codewriter.write_push(&MemorySegment::Static, 2, assembly)).first(),
+            Some(&Instruction::at("$$Lib.2"))
+        );
+        assert_eq!(
+            written(|assembly| codewriter.write_label("END", assembly)),
+            [Instruction::label("$$TRUE.1$END")]
+        );
+        assert_eq!(
+            written(|assembly| codewriter.write_label("$END", assembly)),
+            [Instruction::label("$$TRUE.1$.$$$END")]
+        );
+    }
+
+    #[test]
+    fn test_dollar_in_names_kept_apart() {
+        let source = "function Main$LOOP 0\nreturn\n\
+                      function Main 0\nlabel LOOP\ngoto LOOP\n\
+                      function f$ 0\nlabel x\ngoto x\n\
+                      function f 0\nlabel $x\ngoto $x";
+        let asm =
+            crate::translator::translate(vec![("Main.vm".to_string(), source.to_string())], false)
+                .unwrap();
+        assert!(asm.contains("(Main$$LOOP)\n"));
+        assert!(asm.contains("(Main$LOOP)\n"));
+        assert!(asm.contains("(f$$$x)\n"));
+        assert!(asm.contains("(f$.$$$x)\n"));
+    }
+}
+
+#[cfg(test)]
+mod comment_tests {
+    use super::*;
+    use crate::hack;
+
+    fn write_with(level: CommentLevel) -> String {
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_comment_level(level);
+        codewriter.set_file_context("Main".to_string());
+        codewriter.set_source_context("Main.vm", 3);
+        hack::print(&codewriter.write(&Command::Push(MemorySegment::Local, 2)))
+    }
+
+    #[test]
+    fn test_no_comments() {
+        assert!(!write_with(CommentLevel::None).contains("//"));
+    }
+
+    #[test]
+    fn test_command_comments() {
+        let asm = write_with(CommentLevel::Command);
+        assert!(asm.starts_with("// push local 2\n"));
+        assert_eq!(asm.matches("//").count(), 1);
+    }
+
+    #[test]
+    fn test_verbose_comments() {
+        let asm = write_with(CommentLevel::Verbose);
+        assert!(asm.starts_with("// Main.vm:3\n// push local 2\n"));
+        assert!(asm.contains("// point to the stack pointer\n@SP"));
+    }
+}
+
+#[cfg(test)]
+mod bootstrap_tests {
+    use super::*;
+    use crate::hack;
+
+    #[test]
+    fn test_configured_bootstrap() {
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_comment_level(CommentLevel::None);
+        codewriter.set_bootstrap(BootstrapConfig {
+            entry: "Main.main".to_string(),
+            sp: Some(300),
+            that: Some(0x8000),
+            ..Default::default()
+        });
+
+        let bootstrap = hack::print(&codewriter.write_bootstrap());
+        assert!(bootstrap.starts_with("@300\nD=A\n@SP\nM=D\n@32767\nD=!A\n@THAT\nM=D\n"));
+        assert!(bootstrap.contains("@Main.main\n0;JMP\n"));
+        assert!(bootstrap.ends_with("($halt)\n@$halt\n0;JMP"));
+
+        let mut epilogue = String::new();
+        Backend::write_epilogue(&mut codewriter, &mut epilogue).unwrap();
+        assert!(!epilogue.contains("$halt"));
+    }
+
+    #[test]
+    fn test_halt_without_bootstrap() {
+        let mut codewriter = CodeWriter::new();
+        let mut epilogue = String::new();
+        Backend::write_epilogue(&mut codewriter, &mut epilogue).unwrap();
+        assert_eq!(epilogue, "($halt)\n@$halt\n0;JMP\n");
+
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_bootstrap(BootstrapConfig {
+            halt: false,
+            ..Default::default()
+        });
+        assert!(!hack::print(&codewriter.write_bootstrap()).contains("$halt"));
+        let mut epilogue = String::new();
+        Backend::write_epilogue(&mut codewriter, &mut epilogue).unwrap();
+        assert!(epilogue.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod memory_tests {
+    use super::*;
+    use crate::hack;
+
+    #[test]
+    fn test_relocated_segments() {
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_comment_level(CommentLevel::None);
+        codewriter.set_stack_checks(true);
+        codewriter.set_profiling(true);
+        codewriter
+            .set_memory_map(MemoryMap {
+                temp: 32,
+                statics: 40..=1023,
+                stack: 1024..=4095,
+                heap: 4096..=31999,
+                traps: 32766,
+                counters: 32000..=32765,
+                ..Default::default()
+            })
+            .unwrap();
+
+        let push = hack::print(
+            &codewriter
+                .write_next(&[Command::Push(MemorySegment::Temp, 2)])
+                .1,
+        );
+        assert!(push.starts_with("@32\nD=A\n@2\nA=D+A\nD=M\n"));
+        assert!(push.contains("@SP\nD=M-1\n@4095\nD=D-A\n@$trap.stack_overflow.0\nD;JGT"));
+        assert!(hack::print(&codewriter.write_bootstrap()).starts_with("@1024\nD=A\n@SP\nM=D\n"));
+
+        let function = hack::print(
+            &codewriter
+                .write_next(&[Command::Function("Main.main".to_string(), 0)])
+                .1,
+        );
+        assert!(function.contains("@32765\nM=M+1\n"));
+        let mut traps = String::new();
+        Backend::write_epilogue(&mut codewriter, &mut traps).unwrap();
+        assert!(traps.contains("@32766\nM=D\n@1\nD=A\n@32767\nM=D\n"));
+    }
+
+    #[test]
+    fn test_overlapping_map_rejected() {
+        let mut codewriter = CodeWriter::new();
+        let overlapping = MemoryMap {
+            statics: 16..=511,
+            ..Default::default()
+        };
+        assert!(codewriter.set_memory_map(overlapping).is_err());
+    }
+}
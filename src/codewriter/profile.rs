@@ -0,0 +1,118 @@
+//! Opt-in profiling. Each function counts its calls in a RAM cell, and the output ends with
+//! a table of which cell counts which function, so a RAM dump from any emulator reads as a
+//! call-count profile.
+
+use super::CodeWriter;
+use crate::hack::{Comp, Dest, Instruction};
+
+impl CodeWriter {
+    /// The first function's counter is the last in `MemoryMap::counters`; the rest follow
+    /// downwards, and functions past the region's start get none
+    fn _counter_address(&self, function_id: usize) -> Option<u16> {
+        let offset = u16::try_from(function_id - 1).ok()?;
+        let counters = &self.memory_map.counters;
+        counters
+            .end()
+            .checked_sub(offset)
+            .filter(|address| address >= counters.start())
+    }
+
+    /// Counts a call to the function being written
+    pub(super) fn _write_counter(&self, assembly: &mut Vec<Instruction>) {
+        if let Some(address) = self._counter_address(self._function_id()) {
+            self._extend(
+                assembly,
+                [
+                    Instruction::comment("count the call"),
+                    Instruction::constant(address),
+                    Instruction::set(Dest::M, Comp::MPlusOne),
+                ],
+            );
+        }
+    }
+
+    /// Checks every function got a counter
+    pub(super) fn _check_counters(&self) -> Result<(), String> {
+        let capacity = self.memory_map.counters.len();
+        if self.profiling && self.functions.len() > capacity {
+            return Err(format!(
+                "Too many functions to profile: {} functions, {} counters",
+                self.functions.len(),
+                capacity
+            ));
+        }
+        Ok(())
+    }
+
+    pub(super) fn _write_counter_table(&self, assembly: &mut Vec<Instruction>) {
+        if !self.profiling || self.functions.is_empty() {
+            return;
+        }
+
+        assembly.push(Instruction::comment("call counters"));
+        let counters = self.functions.iter().enumerate().map_while(|(i, name)| {
+            let address = self._counter_address(i + 1)?;
+            Some(Instruction::comment(format!("{}: {}", address, name)))
+        });
+        assembly.extend(counters);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Backend;
+    use crate::codewriter::CommentLevel;
+    use crate::command::Command;
+    use crate::hack;
+    use crate::hack::MemoryMap;
+
+    #[test]
+    fn test_functions_count_calls() {
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_profiling(true);
+        codewriter.set_comment_level(CommentLevel::None);
+
+        assert_eq!(
+            hack::print(&codewriter.write(&Command::Function("Main.main".to_string(), 0))),
+            "(Main.main)\n@2045\nM=M+1"
+        );
+        assert!(
+            hack::print(&codewriter.write(&Command::Function("Main.f".to_string(), 1)))
+                .starts_with("(Main.f)\n@2044\nM=M+1\n")
+        );
+
+        let mut table = String::new();
+        Backend::write_epilogue(&mut codewriter, &mut table).unwrap();
+        assert!(table.ends_with("// call counters\n// 2045: Main.main\n// 2044: Main.f\n"));
+    }
+
+    #[test]
+    fn test_counters_stay_in_region() {
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_profiling(true);
+        codewriter.set_comment_level(CommentLevel::None);
+        codewriter
+            .set_memory_map(MemoryMap {
+                counters: 2044..=2045,
+                ..Default::default()
+            })
+            .unwrap();
+
+        for name in ["Main.main", "Main.f", "Main.g"] {
+            codewriter.write(&Command::Function(name.to_string(), 0));
+        }
+        assert_eq!(
+            hack::print(&codewriter.write(&Command::Function("Main.h".to_string(), 0))),
+            "(Main.h)"
+        );
+
+        let mut table = String::new();
+        Backend::write_epilogue(&mut codewriter, &mut table).unwrap();
+        assert!(table.ends_with("// call counters\n// 2045: Main.main\n// 2044: Main.f\n"));
+        assert_eq!(
+            Backend::check(&mut codewriter).unwrap_err(),
+            "Too many functions to profile: 4 functions, 2 counters"
+        );
+    }
+}
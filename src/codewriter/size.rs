@@ -0,0 +1,261 @@
+//! Code size accounting. Every instruction written is counted against the function and the
+//! kind of command it was written for, so a program that outgrows the ROM shows where its
+//! code goes.
+
+use super::{CodeWriter, Context};
+use crate::command::{Command, MemorySegment};
+use crate::hack::Instruction;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Instructions counted so far, by function and by command kind
+#[derive(Debug, Default)]
+pub(super) struct Sizes {
+    total: usize,
+    functions: HashMap<String, usize>,
+    commands: HashMap<String, usize>,
+}
+
+impl Sizes {
+    /// Counts `assembly`'s instructions, leaving out labels and comments, which take no ROM
+    pub fn add(&mut self, function: &str, command: &str, assembly: &[Instruction]) {
+        let count = assembly
+            .iter()
+            .filter(|instruction| instruction.is_code())
+            .count();
+        self.total += count;
+        add_to(&mut self.functions, function, count);
+        add_to(&mut self.commands, command, count);
+    }
+}
+
+/// Adds to `name`'s count, only copying the name the first time it's seen
+fn add_to(counts: &mut HashMap<String, usize>, name: &str, count: usize) {
+    match counts.get_mut(name) {
+        Some(total) => *total += count,
+        None => {
+            counts.insert(name.to_string(), count);
+        }
+    }
+}
+
+/// How many instructions a program takes, largest contributors first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeReport {
+    pub total: usize,
+    pub budget: usize,
+    pub functions: Vec<(String, usize)>,
+    pub commands: Vec<(String, usize)>,
+}
+
+/// How many of the largest functions an over-budget error lists; the rest share one row
+const LISTED_FUNCTIONS: usize = 10;
+
+fn sorted(counts: &HashMap<String, usize>) -> Vec<(String, usize)> {
+    let mut counts: Vec<(String, usize)> = counts
+        .iter()
+        .map(|(name, count)| (name.clone(), *count))
+        .collect();
+    counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    counts
+}
+
+impl SizeReport {
+    /// Keeps the `n` largest functions, counting the rest together
+    fn largest_functions(mut self, n: usize) -> Self {
+        if self.functions.len() > n {
+            let rest = self.functions.split_off(n);
+            let count = rest.iter().map(|(_, count)| count).sum();
+            self.functions
+                .push((format!("({} more functions)", rest.len()), count));
+        }
+        self
+    }
+}
+
+impl fmt::Display for SizeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} of {} instructions", self.total, self.budget)?;
+        for (heading, counts) in [("function", &self.functions), ("command", &self.commands)] {
+            writeln!(f, "by {}:", heading)?;
+            for (name, count) in counts {
+                writeln!(f, "{:>8}  {}", count, name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What `command`'s code is counted as: its keyword, and for pushes and pops the segment.
+/// Commands fused into one block are counted as the first.
+pub(super) fn command_kind(command: &Command) -> &'static str {
+    use MemorySegment::*;
+    match command {
+        Command::Push(segment, _) => match segment {
+            Constant => "push constant",
+            Local => "push local",
+            Argument => "push argument",
+            This => "push this",
+            That => "push that",
+            Temp => "push temp",
+            Pointer => "push pointer",
+            Static => "push static",
+        },
+        Command::Pop(segment, _) => match segment {
+            Constant => "pop constant",
+            Local => "pop local",
+            Argument => "pop argument",
+            This => "pop this",
+            That => "pop that",
+            Temp => "pop temp",
+            Pointer => "pop pointer",
+            Static => "pop static",
+        },
+        Command::Add => "add",
+        Command::Sub => "sub",
+        Command::Neg => "neg",
+        Command::Eq => "eq",
+        Command::Gt => "gt",
+        Command::Lt => "lt",
+        Command::And => "and",
+        Command::Or => "or",
+        Command::Not => "not",
+        Command::Label(_) => "label",
+        Command::Goto(_) => "goto",
+        Command::IfGoto(_) => "if-goto",
+        Command::Function(..) => "function",
+        Command::Call(..) => "call",
+        Command::Return => "return",
+        Command::Placeholder => "placeholder",
+    }
+}
+
+impl Context {
+    /// What the code being written is counted under: its function, or, like the bootstrap and
+    /// runtime, a parenthesized name for code outside any function
+    pub(super) fn counted_as(&self) -> Cow<'_, str> {
+        if self.function.is_empty() {
+            Cow::Owned(format!("(top level of {})", self.file))
+        } else {
+            Cow::Borrowed(&self.function)
+        }
+    }
+}
+
+impl CodeWriter {
+    pub fn size_report(&self) -> SizeReport {
+        SizeReport {
+            total: self.sizes.total,
+            budget: self.rom_budget,
+            functions: sorted(&self.sizes.functions),
+            commands: sorted(&self.sizes.commands),
+        }
+    }
+
+    pub(super) fn _check_size(&self) -> Result<(), String> {
+        if self.sizes.total > self.rom_budget {
+            let report = self.size_report().largest_functions(LISTED_FUNCTIONS);
+            Err(format!("Program too large for ROM: {}", report))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hack;
+    use crate::sourcemap::SourceMap;
+    use crate::translator;
+
+    const PROGRAM: &str = "function Main.main 0\npush constant 1\npush constant 2\nadd\n\
+                           call Main.f 0\nreturn\nfunction Main.f 0\npush constant 3\nreturn";
+
+    fn translate(codewriter: &mut CodeWriter) -> Result<(String, SourceMap), String> {
+        let inputs = vec![("Main.vm".to_string(), PROGRAM.to_string())];
+        translator::translate_with(codewriter, inputs, false)
+    }
+
+    #[test]
+    fn test_instructions_counted() {
+        let mut codewriter = CodeWriter::new();
+        let (asm, _) = translate(&mut codewriter).unwrap();
+        let report = codewriter.size_report();
+
+        let code = hack::parse(&asm).unwrap();
+        assert_eq!(
+            report.total,
+            code.iter()
+                .filter(|instruction| instruction.is_code())
+                .count()
+        );
+        assert_eq!(report.budget, 32768);
+        assert_eq!(report.functions[0].0, "Main.main");
+        assert!(report
+            .functions
+            .windows(2)
+            .all(|pair| pair[0].1 >= pair[1].1));
+        assert!(report
+            .commands
+            .iter()
+            .any(|(kind, _)| kind == "push constant"));
+        assert!(report.commands.iter().any(|(kind, _)| kind == "(runtime)"));
+        assert_eq!(
+            report
+                .functions
+                .iter()
+                .map(|(_, count)| count)
+                .sum::<usize>(),
+            report.total
+        );
+    }
+
+    #[test]
+    fn test_top_level_code_labeled() {
+        let mut codewriter = CodeWriter::new();
+        let inputs = vec![(
+            "Main.vm".to_string(),
+            "push constant 1\npop temp 0".to_string(),
+        )];
+        translator::translate_with(&mut codewriter, inputs, false).unwrap();
+
+        let report = codewriter.size_report();
+        assert!(report.to_string().contains("  (top level of Main)\n"));
+    }
+
+    #[test]
+    fn test_over_budget_fails() {
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_rom_budget(20);
+
+        let error = translate(&mut codewriter).unwrap_err();
+        assert!(error.starts_with("Program too large for ROM: "));
+        assert!(error.contains(" of 20 instructions\nby function:\n"));
+        assert!(error.contains("  Main.main\n"));
+    }
+
+    #[test]
+    fn test_over_budget_lists_largest_functions() {
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_rom_budget(20);
+        let source: String = (0..15)
+            .map(|i| format!("function Main.f{} 0\npush constant {}\nreturn\n", i, i))
+            .collect();
+
+        let error = translator::translate_with(
+            &mut codewriter,
+            vec![("Main.vm".to_string(), source)],
+            false,
+        )
+        .unwrap_err();
+        let listed = error
+            .lines()
+            .skip_while(|line| *line != "by function:")
+            .take_while(|line| *line != "by command:")
+            .count();
+        assert_eq!(listed, 12);
+        assert!(error.contains("  (6 more functions)\n"));
+    }
+}
@@ -0,0 +1,274 @@
+//! Instruction selection by tiling: multi-command VM patterns that have a shorter
+//! Hack lowering than their commands written one at a time.
+
+use super::CodeWriter;
+use crate::command::{Command, MemorySegment};
+use crate::hack::{Comp, Dest, Instruction};
+
+/// Matches a pattern at the front of `commands` and, if it applies, writes their assembly
+/// and returns how many commands it covers
+type Tile = fn(&mut CodeWriter, &[Command], &mut Vec<Instruction>) -> Option<usize>;
+
+/// Tiles in priority order -- longer patterns come first so they win over their prefixes
+pub(super) const TILES: [Tile; 6] = [
+    increment,
+    negated_compare_branch,
+    compare_branch,
+    memory_move,
+    push_minus_one,
+    push_small_constant,
+];
+
+/// `push x i; push constant 1; add|sub; pop x i` updates x[i] in place
+fn increment(
+    codewriter: &mut CodeWriter,
+    commands: &[Command],
+    assembly: &mut Vec<Instruction>,
+) -> Option<usize> {
+    match commands {
+        [Command::Push(source, i), Command::Push(MemorySegment::Constant, 1), op @ (Command::Add | Command::Sub), Command::Pop(target, j), ..]
+            if source == target && i == j && *source != MemorySegment::Constant =>
+        {
+            let update = if *op == Command::Add {
+                Comp::MPlusOne
+            } else {
+                Comp::MMinusOne
+            };
+            codewriter._segment_address(source, *i, assembly);
+            assembly.push(Instruction::set(Dest::M, update));
+            Some(4)
+        }
+        _ => None,
+    }
+}
+
+/// `eq|gt|lt; not; if-goto label` jumps when the comparison fails
+fn negated_compare_branch(
+    codewriter: &mut CodeWriter,
+    commands: &[Command],
+    assembly: &mut Vec<Instruction>,
+) -> Option<usize> {
+    match commands {
+        [comparison @ (Command::Eq | Command::Gt | Command::Lt), Command::Not, Command::IfGoto(label), ..] =>
+        {
+            codewriter.write_compare_branch(comparison, label, true, assembly);
+            Some(3)
+        }
+        _ => None,
+    }
+}
+
+/// `eq|gt|lt; if-goto label` jumps when the comparison holds
+fn compare_branch(
+    codewriter: &mut CodeWriter,
+    commands: &[Command],
+    assembly: &mut Vec<Instruction>,
+) -> Option<usize> {
+    match commands {
+        [comparison @ (Command::Eq | Command::Gt | Command::Lt), Command::IfGoto(label), ..] => {
+            codewriter.write_compare_branch(comparison, label, false, assembly);
+            Some(2)
+        }
+        _ => None,
+    }
+}
+
+/// `push x i; pop y j` copies memory without going through the stack
+fn memory_move(
+    codewriter: &mut CodeWriter,
+    commands: &[Command],
+    assembly: &mut Vec<Instruction>,
+) -> Option<usize> {
+    let [Command::Push(source, i), Command::Pop(target, j), ..] = commands else {
+        return None;
+    };
+
+    match (source, *i) {
+        // small constants can be written without going through D
+        (MemorySegment::Constant, value @ (0 | 1)) => {
+            let value = if value == 0 { Comp::Zero } else { Comp::One };
+            codewriter._segment_address(target, *j, assembly);
+            assembly.push(Instruction::set(Dest::M, value));
+        }
+        _ if codewriter._address_clobbers_d(target, *j) => {
+            codewriter._extend(
+                assembly,
+                [Instruction::comment(
+                    "stash the target address in a general-purpose register",
+                )],
+            );
+            codewriter._get_base_address(target, assembly);
+            codewriter._extend(
+                assembly,
+                [
+                    Instruction::set(Dest::D, Comp::A),
+                    Instruction::constant(*j),
+                    Instruction::set(Dest::D, Comp::DPlusA),
+                    Instruction::at("R13"),
+                    Instruction::set(Dest::M, Comp::D),
+                    Instruction::comment("load the source value into D"),
+                ],
+            );
+            codewriter._load_value(source, *i, assembly);
+            codewriter._extend(
+                assembly,
+                [
+                    Instruction::comment("store D into *R13"),
+                    Instruction::at("R13"),
+                    Instruction::set(Dest::A, Comp::M),
+                    Instruction::set(Dest::M, Comp::D),
+                ],
+            );
+        }
+        _ => {
+            codewriter._load_value(source, *i, assembly);
+            codewriter._segment_address(target, *j, assembly);
+            assembly.push(Instruction::set(Dest::M, Comp::D));
+        }
+    }
+
+    Some(2)
+}
+
+/// `push constant 1; neg` or `push constant 0; not` pushes true (-1)
+fn push_minus_one(
+    codewriter: &mut CodeWriter,
+    commands: &[Command],
+    assembly: &mut Vec<Instruction>,
+) -> Option<usize> {
+    match commands {
+        [Command::Push(MemorySegment::Constant, 1), Command::Neg, ..]
+        | [Command::Push(MemorySegment::Constant, 0), Command::Not, ..] => {
+            write_push_literal(codewriter, Comp::MinusOne, assembly);
+            Some(2)
+        }
+        _ => None,
+    }
+}
+
+/// `push constant 0|1` writes the constant without going through D
+fn push_small_constant(
+    codewriter: &mut CodeWriter,
+    commands: &[Command],
+    assembly: &mut Vec<Instruction>,
+) -> Option<usize> {
+    match commands {
+        [Command::Push(MemorySegment::Constant, value @ (0 | 1)), ..] => {
+            let value = if *value == 0 { Comp::Zero } else { Comp::One };
+            write_push_literal(codewriter, value, assembly);
+            Some(1)
+        }
+        _ => None,
+    }
+}
+
+/// Pushes one of the literals the ALU can produce directly (-1, 0 or 1)
+fn write_push_literal(codewriter: &CodeWriter, value: Comp, assembly: &mut Vec<Instruction>) {
+    codewriter._extend(
+        assembly,
+        [
+            Instruction::comment("point to stack pointer"),
+            Instruction::at("SP"),
+            Instruction::comment("grow the stack"),
+            Instruction::set(Dest::M, Comp::MPlusOne),
+            Instruction::comment("point to the new top of the stack"),
+            Instruction::set(Dest::A, Comp::MMinusOne),
+            Instruction::set(Dest::M, value),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hack;
+
+    fn write_next(codewriter: &mut CodeWriter, commands: &[Command]) -> (usize, String) {
+        let (consumed, assembly) = codewriter.write_next(commands);
+        (consumed, hack::print(&assembly))
+    }
+
+    #[test]
+    fn test_comparison_fused_with_ifgoto() {
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_file_context("Test".to_string());
+        let commands = [
+            Command::Lt,
+            Command::IfGoto("LOOP".to_string()),
+            Command::Add,
+        ];
+
+        let (consumed, asm) = write_next(&mut codewriter, &commands);
+        assert_eq!(consumed, 2);
+        assert!(asm.ends_with("@Test$LOOP\nD;JLT"));
+        assert!(!asm.contains("$TRUE"));
+    }
+
+    #[test]
+    fn test_negated_comparison_fused_with_ifgoto() {
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_file_context("Test".to_string());
+        let commands = [
+            Command::Eq,
+            Command::Not,
+            Command::IfGoto("END".to_string()),
+        ];
+
+        let (consumed, asm) = write_next(&mut codewriter, &commands);
+        assert_eq!(consumed, 3);
+        assert!(asm.ends_with("@Test$END\nD;JNE"));
+    }
+
+    #[test]
+    fn test_increment_in_place() {
+        let mut codewriter = CodeWriter::new();
+        let commands = [
+            Command::Push(MemorySegment::Local, 2),
+            Command::Push(MemorySegment::Constant, 1),
+            Command::Add,
+            Command::Pop(MemorySegment::Local, 2),
+        ];
+
+        let (consumed, asm) = write_next(&mut codewriter, &commands);
+        assert_eq!(consumed, 4);
+        assert!(asm.ends_with("@LCL\nA=M+1\nA=A+1\nM=M+1"));
+    }
+
+    #[test]
+    fn test_direct_memory_move() {
+        let mut codewriter = CodeWriter::new();
+        let commands = [
+            Command::Push(MemorySegment::Temp, 3),
+            Command::Pop(MemorySegment::Pointer, 1),
+        ];
+
+        let (consumed, asm) = write_next(&mut codewriter, &commands);
+        assert_eq!(consumed, 2);
+        assert!(asm.ends_with("@8\nD=M\n@4\nM=D"));
+    }
+
+    #[test]
+    fn test_push_small_constants() {
+        let mut codewriter = CodeWriter::new();
+        let commands = [Command::Push(MemorySegment::Constant, 1), Command::Neg];
+
+        let (consumed, asm) = write_next(&mut codewriter, &commands);
+        assert_eq!(consumed, 2);
+        assert!(asm.ends_with("M=-1"));
+
+        let (consumed, asm) = write_next(&mut codewriter, &commands[..1]);
+        assert_eq!(consumed, 1);
+        assert!(asm.ends_with("M=1"));
+    }
+
+    #[test]
+    fn test_unfused_comparison() {
+        let mut codewriter = CodeWriter::new();
+        let commands = [Command::Gt, Command::Label("X".to_string())];
+
+        let (consumed, asm) = write_next(&mut codewriter, &commands);
+        assert_eq!(consumed, 1);
+        assert!(asm.contains("D;JGT"));
+        assert!(asm.contains("($TRUE.1)"));
+    }
+}
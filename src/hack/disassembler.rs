@@ -0,0 +1,125 @@
+use super::{Instruction, Value};
+use crate::sourcemap::SourceMap;
+use std::collections::BTreeSet;
+
+/// Turns `.hack` text back into assembly. Addresses loaded right before a jump get
+/// synthesized `(L{address})` labels, and given the translator's source map, each run of
+/// instructions from one VM command is headed by a comment naming it.
+pub fn disassemble(machine_code: &str, source_map: Option<&SourceMap>) -> Result<String, String> {
+    let program = machine_code
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            let line = line.trim();
+            if line.len() != 16 {
+                return Err(format!("{}: Not a 16-bit word: {}", number + 1, line));
+            }
+            let word = u16::from_str_radix(line, 2)
+                .map_err(|_| format!("{}: Not a 16-bit word: {}", number + 1, line))?;
+            Instruction::decode(word).map_err(|error| format!("{}: {}", number + 1, error))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let is_jump_target = |address: u16, next: Option<&Instruction>| {
+        matches!(next, Some(Instruction::C { jump: Some(_), .. }))
+            && address as usize <= program.len()
+    };
+    let targets: BTreeSet<usize> = program
+        .iter()
+        .enumerate()
+        .filter_map(|(rom_address, instruction)| match instruction {
+            Instruction::A(Value::Constant(address))
+                if is_jump_target(*address, program.get(rom_address + 1)) =>
+            {
+                Some(*address as usize)
+            }
+            _ => None,
+        })
+        .collect();
+
+    let mut lines = Vec::new();
+    let mut current_source = None;
+    for (rom_address, instruction) in program.iter().enumerate() {
+        if let Some(source_map) = source_map {
+            let source = source_map.rom_addresses.get(rom_address).copied().flatten();
+            if source != current_source {
+                if let Some(index) = source {
+                    let source = source_map
+                        .sources
+                        .get(index)
+                        .ok_or(format!("Source map has no source {}", index))?;
+                    lines.push(format!(
+                        "// {}:{}: {}",
+                        source.file, source.line, source.command
+                    ));
+                }
+                current_source = source;
+            }
+        }
+        if targets.contains(&rom_address) {
+            lines.push(format!("(L{})", rom_address));
+        }
+
+        match instruction {
+            Instruction::A(Value::Constant(address))
+                if is_jump_target(*address, program.get(rom_address + 1)) =>
+            {
+                lines.push(format!("@L{}", address));
+            }
+            instruction => lines.push(instruction.to_string()),
+        }
+    }
+    if targets.contains(&program.len()) {
+        lines.push(format!("(L{})", program.len()));
+    }
+
+    Ok(lines.into_iter().map(|line| line + "\n").collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::assemble;
+    use super::*;
+    use crate::sourcemap::Source;
+
+    #[test]
+    fn test_round_trip_with_synthesized_labels() {
+        let assembly = "@5\nD=A\n(LOOP)\n@LOOP\nD=D-1;JGT\n@i\nM=D\n@LOOP\n0;JMP\n";
+        let machine_code = assemble(assembly).unwrap();
+
+        assert_eq!(
+            disassemble(&machine_code, None).unwrap(),
+            "@5\nD=A\n(L2)\n@L2\nD=D-1;JGT\n@16\nM=D\n@L2\n0;JMP\n"
+        );
+    }
+
+    #[test]
+    fn test_source_map_annotations() {
+        let mut source_map = SourceMap::new();
+        source_map.add("@256\nD=A", None);
+        source_map.add(
+            "// push constant 7\n@7\nD=A",
+            Some(Source {
+                file: "Main.vm".into(),
+                line: 3,
+                command: "push constant 7".to_string(),
+            }),
+        );
+        let machine_code = assemble("@256\nD=A\n@7\nD=A").unwrap();
+
+        assert_eq!(
+            disassemble(&machine_code, Some(&source_map)).unwrap(),
+            "@256\nD=A\n// Main.vm:3: push constant 7\n@7\nD=A\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid_words() {
+        assert!(disassemble("0101", None).is_err());
+        assert_eq!(
+            disassemble("1111111111111111", None).unwrap_err(),
+            "1: Invalid comp bits in 1111111111111111"
+        );
+    }
+}
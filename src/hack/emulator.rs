@@ -0,0 +1,57 @@
+//! A minimal Hack CPU, for tests that run the generated code
+
+use super::{encode, parse};
+
+/// Assembles `assembly` and runs it from address 0 on `ram` until it halts, which every
+/// program here does by jumping back to the `@` in front of the jump. Returns whether it
+/// halted within `steps` instructions.
+pub fn run(assembly: &str, ram: &mut [i16], steps: usize) -> bool {
+    let rom = encode(&parse(assembly).unwrap()).unwrap();
+    let (mut a, mut d, mut pc) = (0i16, 0i16, 0usize);
+    for _ in 0..steps {
+        let instruction = rom[pc];
+        if instruction & 0x8000 == 0 {
+            a = instruction as i16;
+            pc += 1;
+            continue;
+        }
+
+        let bit = |n: u16| instruction & (1 << n) != 0;
+        let mut x = d;
+        let mut y = if bit(12) { ram[a as u16 as usize] } else { a };
+        if bit(11) {
+            x = 0;
+        }
+        if bit(10) {
+            x = !x;
+        }
+        if bit(9) {
+            y = 0;
+        }
+        if bit(8) {
+            y = !y;
+        }
+        let mut out = if bit(7) { x.wrapping_add(y) } else { x & y };
+        if bit(6) {
+            out = !out;
+        }
+
+        let address = a as u16 as usize;
+        if bit(3) {
+            ram[address] = out;
+        }
+        if bit(4) {
+            d = out;
+        }
+        if bit(5) {
+            a = out;
+        }
+        let jump = (bit(2) && out < 0) || (bit(1) && out == 0) || (bit(0) && out > 0);
+        let next = if jump { a as u16 as usize } else { pc + 1 };
+        if next + 1 == pc && rom[next] as usize == next {
+            return true;
+        }
+        pc = next;
+    }
+    false
+}
@@ -0,0 +1,147 @@
+use super::{MAX_CONSTANT, VARIABLE_BASE};
+use std::ops::RangeInclusive;
+
+/// Where the VM's segments live in RAM. The defaults are the standard Hack layout, less the
+/// stack's last 256 words; variants of the machine may move them, as long as no two regions
+/// overlap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryMap {
+    /// The first of the 8 temp registers
+    pub temp: u16,
+    /// The first of the 2 pointer registers, which hold THIS and THAT
+    pub pointer: u16,
+    /// Where the assembler allocates variables, statics included, in order of first use
+    pub statics: RangeInclusive<u16>,
+    /// Where the stack grows, upward from its first address
+    pub stack: RangeInclusive<u16>,
+    pub heap: RangeInclusive<u16>,
+    /// The 2 slots a failed runtime check reports in: the id of the function it happened in,
+    /// then the trap's code
+    pub traps: u16,
+    /// Where profiling counts calls, one slot per function from the last address down
+    pub counters: RangeInclusive<u16>,
+}
+
+/// SP, LCL and ARG stay where the Hack CPU's programs expect them
+const SEGMENT_POINTERS: RangeInclusive<u16> = 0..=2;
+/// Scratch registers the generated code uses
+const SCRATCH: RangeInclusive<u16> = 13..=15;
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        MemoryMap {
+            temp: 5,
+            pointer: 3,
+            statics: VARIABLE_BASE..=255,
+            // traps and counters sit at the top of the stack's RAM, out of the OS's heap
+            stack: 256..=1791,
+            heap: 2048..=16383,
+            traps: 2046,
+            counters: 1792..=2045,
+        }
+    }
+}
+
+impl MemoryMap {
+    /// Checks every region can be addressed by an A-instruction, and no two overlap,
+    /// including the registers that can't move
+    pub fn validate(&self) -> Result<(), String> {
+        let regions = [
+            ("segment pointers", SEGMENT_POINTERS),
+            ("pointer", self.pointer..=self.pointer.saturating_add(1)),
+            ("temp", self.temp..=self.temp.saturating_add(7)),
+            ("scratch registers", SCRATCH),
+            ("statics", self.statics.clone()),
+            ("stack", self.stack.clone()),
+            ("heap", self.heap.clone()),
+            ("traps", self.traps..=self.traps.saturating_add(1)),
+            ("counters", self.counters.clone()),
+        ];
+
+        for (name, region) in &regions {
+            if region.is_empty() {
+                return Err(format!("Memory region {} is empty", name));
+            }
+            if *region.end() > MAX_CONSTANT {
+                return Err(format!(
+                    "Memory region {} ({}-{}) goes past {}",
+                    name,
+                    region.start(),
+                    region.end(),
+                    MAX_CONSTANT
+                ));
+            }
+        }
+        for (i, (name, region)) in regions.iter().enumerate() {
+            for (other_name, other) in &regions[..i] {
+                if region.start() <= other.end() && other.start() <= region.end() {
+                    return Err(format!(
+                        "Memory regions {} ({}-{}) and {} ({}-{}) overlap",
+                        other_name,
+                        other.start(),
+                        other.end(),
+                        name,
+                        region.start(),
+                        region.end()
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_is_valid() {
+        assert!(MemoryMap::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_overlap_rejected() {
+        let map = MemoryMap {
+            stack: 1024..=4095,
+            ..Default::default()
+        };
+        assert_eq!(
+            map.validate().unwrap_err(),
+            "Memory regions stack (1024-4095) and heap (2048-16383) overlap"
+        );
+
+        let map = MemoryMap {
+            temp: 10,
+            ..Default::default()
+        };
+        assert!(map.validate().unwrap_err().contains("temp (10-17)"));
+
+        let map = MemoryMap {
+            stack: 256..=2047,
+            ..Default::default()
+        };
+        assert_eq!(
+            map.validate().unwrap_err(),
+            "Memory regions stack (256-2047) and traps (2046-2047) overlap"
+        );
+    }
+
+    #[test]
+    fn test_unaddressable_region_rejected() {
+        let map = MemoryMap {
+            stack: 16384..=65535,
+            ..Default::default()
+        };
+        assert_eq!(
+            map.validate().unwrap_err(),
+            "Memory region stack (16384-65535) goes past 32767"
+        );
+
+        let map = MemoryMap {
+            pointer: 32767,
+            ..Default::default()
+        };
+        assert!(map.validate().is_err());
+    }
+}
@@ -0,0 +1,591 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+
+mod disassembler;
+#[cfg(test)]
+pub(crate) mod emulator;
+mod memory;
+
+pub use disassembler::disassemble;
+pub use memory::MemoryMap;
+
+/// Symbols the Hack assembler defines before reading a program
+pub const PREDEFINED_SYMBOLS: [(&str, u16); 23] = [
+    ("SP", 0),
+    ("LCL", 1),
+    ("ARG", 2),
+    ("THIS", 3),
+    ("THAT", 4),
+    ("R0", 0),
+    ("R1", 1),
+    ("R2", 2),
+    ("R3", 3),
+    ("R4", 4),
+    ("R5", 5),
+    ("R6", 6),
+    ("R7", 7),
+    ("R8", 8),
+    ("R9", 9),
+    ("R10", 10),
+    ("R11", 11),
+    ("R12", 12),
+    ("R13", 13),
+    ("R14", 14),
+    ("R15", 15),
+    ("SCREEN", 16384),
+    ("KBD", 24576),
+];
+
+/// The largest value an A-instruction can load, and so the highest address code can reach
+/// directly
+pub const MAX_CONSTANT: u16 = 0x7FFF;
+
+/// How many instructions the Hack ROM holds
+pub const ROM_SIZE: usize = 32768;
+
+/// Variables are allocated upward from here, in order of first use
+pub const VARIABLE_BASE: u16 = 16;
+
+/// The operand of an A-instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Constant(u16),
+    /// Borrowed for the registers and other fixed symbols, so most instructions allocate nothing
+    Symbol(Cow<'static, str>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    A(Value),
+    C {
+        dest: Option<Dest>,
+        comp: Comp,
+        jump: Option<Jump>,
+    },
+    Label(String),
+    /// Commentary for readers of the assembly; takes no ROM
+    Comment(Cow<'static, str>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dest {
+    M,
+    D,
+    MD,
+    A,
+    AM,
+    AD,
+    AMD,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comp {
+    Zero,
+    One,
+    MinusOne,
+    D,
+    A,
+    M,
+    NotD,
+    NotA,
+    NotM,
+    NegD,
+    NegA,
+    NegM,
+    DPlusOne,
+    APlusOne,
+    MPlusOne,
+    DMinusOne,
+    AMinusOne,
+    MMinusOne,
+    DPlusA,
+    DPlusM,
+    DMinusA,
+    DMinusM,
+    AMinusD,
+    MMinusD,
+    DAndA,
+    DAndM,
+    DOrA,
+    DOrM,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jump {
+    JGT,
+    JEQ,
+    JGE,
+    JLT,
+    JNE,
+    JLE,
+    JMP,
+}
+
+/// Each computation's mnemonic and its `a c1..c6` bits
+const COMPS: [(Comp, &str, u16); 28] = [
+    (Comp::Zero, "0", 0b0101010),
+    (Comp::One, "1", 0b0111111),
+    (Comp::MinusOne, "-1", 0b0111010),
+    (Comp::D, "D", 0b0001100),
+    (Comp::A, "A", 0b0110000),
+    (Comp::M, "M", 0b1110000),
+    (Comp::NotD, "!D", 0b0001101),
+    (Comp::NotA, "!A", 0b0110001),
+    (Comp::NotM, "!M", 0b1110001),
+    (Comp::NegD, "-D", 0b0001111),
+    (Comp::NegA, "-A", 0b0110011),
+    (Comp::NegM, "-M", 0b1110011),
+    (Comp::DPlusOne, "D+1", 0b0011111),
+    (Comp::APlusOne, "A+1", 0b0110111),
+    (Comp::MPlusOne, "M+1", 0b1110111),
+    (Comp::DMinusOne, "D-1", 0b0001110),
+    (Comp::AMinusOne, "A-1", 0b0110010),
+    (Comp::MMinusOne, "M-1", 0b1110010),
+    (Comp::DPlusA, "D+A", 0b0000010),
+    (Comp::DPlusM, "D+M", 0b1000010),
+    (Comp::DMinusA, "D-A", 0b0010011),
+    (Comp::DMinusM, "D-M", 0b1010011),
+    (Comp::AMinusD, "A-D", 0b0000111),
+    (Comp::MMinusD, "M-D", 0b1000111),
+    (Comp::DAndA, "D&A", 0b0000000),
+    (Comp::DAndM, "D&M", 0b1000000),
+    (Comp::DOrA, "D|A", 0b0010101),
+    (Comp::DOrM, "D|M", 0b1010101),
+];
+
+/// Commuted spellings assemblers commonly accept
+const COMP_ALIASES: [(&str, Comp); 9] = [
+    ("1+D", Comp::DPlusOne),
+    ("1+A", Comp::APlusOne),
+    ("1+M", Comp::MPlusOne),
+    ("A+D", Comp::DPlusA),
+    ("M+D", Comp::DPlusM),
+    ("A&D", Comp::DAndA),
+    ("M&D", Comp::DAndM),
+    ("A|D", Comp::DOrA),
+    ("M|D", Comp::DOrM),
+];
+
+const DESTS: [(Dest, &str); 7] = [
+    (Dest::M, "M"),
+    (Dest::D, "D"),
+    (Dest::MD, "MD"),
+    (Dest::A, "A"),
+    (Dest::AM, "AM"),
+    (Dest::AD, "AD"),
+    (Dest::AMD, "AMD"),
+];
+
+const JUMPS: [(Jump, &str); 7] = [
+    (Jump::JGT, "JGT"),
+    (Jump::JEQ, "JEQ"),
+    (Jump::JGE, "JGE"),
+    (Jump::JLT, "JLT"),
+    (Jump::JNE, "JNE"),
+    (Jump::JLE, "JLE"),
+    (Jump::JMP, "JMP"),
+];
+
+impl Comp {
+    pub fn parse(mnemonic: &str) -> Option<Comp> {
+        COMPS
+            .iter()
+            .map(|(comp, name, _)| (*name, *comp))
+            .chain(COMP_ALIASES)
+            .find(|(name, _)| *name == mnemonic)
+            .map(|(_, comp)| comp)
+    }
+
+    pub fn bits(self) -> u16 {
+        COMPS.iter().find(|(comp, _, _)| *comp == self).unwrap().2
+    }
+}
+
+impl Dest {
+    /// Accepts the destinations in any order, e.g. `DM` as well as `MD`
+    pub fn parse(mnemonic: &str) -> Option<Dest> {
+        let sorted = |name: &str| {
+            let mut registers: Vec<char> = name.chars().collect();
+            registers.sort_unstable();
+            registers
+        };
+        DESTS
+            .iter()
+            .find(|(_, name)| sorted(name) == sorted(mnemonic))
+            .map(|(dest, _)| *dest)
+    }
+
+    /// The `d1 d2 d3` bits: A, D and M
+    pub fn bits(self) -> u16 {
+        let name = self.to_string();
+        ['A', 'D', 'M'].iter().fold(0, |bits, register| {
+            bits << 1 | name.contains(*register) as u16
+        })
+    }
+}
+
+impl Jump {
+    pub fn parse(mnemonic: &str) -> Option<Jump> {
+        JUMPS
+            .iter()
+            .find(|(_, name)| *name == mnemonic)
+            .map(|(jump, _)| *jump)
+    }
+
+    /// The `j1 j2 j3` bits: jump if negative, zero, positive
+    pub fn bits(self) -> u16 {
+        JUMPS.iter().position(|(jump, _)| *jump == self).unwrap() as u16 + 1
+    }
+}
+
+impl Instruction {
+    /// `@symbol`
+    pub fn at(symbol: impl Into<Cow<'static, str>>) -> Instruction {
+        Instruction::A(Value::Symbol(symbol.into()))
+    }
+
+    /// `@constant`
+    pub fn constant(constant: u16) -> Instruction {
+        Instruction::A(Value::Constant(constant))
+    }
+
+    /// `dest=comp`
+    pub fn set(dest: Dest, comp: Comp) -> Instruction {
+        Instruction::C {
+            dest: Some(dest),
+            comp,
+            jump: None,
+        }
+    }
+
+    /// `comp;jump`
+    pub fn jump(comp: Comp, jump: Jump) -> Instruction {
+        Instruction::C {
+            dest: None,
+            comp,
+            jump: Some(jump),
+        }
+    }
+
+    pub fn label(label: impl Into<String>) -> Instruction {
+        Instruction::Label(label.into())
+    }
+
+    pub fn comment(text: impl Into<Cow<'static, str>>) -> Instruction {
+        Instruction::Comment(text.into())
+    }
+
+    /// Whether the instruction occupies a word of ROM
+    pub fn is_code(&self) -> bool {
+        matches!(self, Instruction::A(_) | Instruction::C { .. })
+    }
+
+    /// Parses one line of assembly, returning `None` for blank lines and comments
+    pub fn parse(line: &str) -> Result<Option<Instruction>, String> {
+        let line = line.split("//").next().unwrap_or_default();
+        let line: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+
+        if line.is_empty() {
+            Ok(None)
+        } else if let Some(label) = line.strip_prefix('(') {
+            let label = label
+                .strip_suffix(')')
+                .ok_or(format!("Unclosed label: {}", line))?;
+            check_symbol(label)?;
+            Ok(Some(Instruction::Label(label.to_string())))
+        } else if let Some(value) = line.strip_prefix('@') {
+            if value.starts_with(|c: char| c.is_ascii_digit()) {
+                match value.parse::<u16>() {
+                    Ok(constant) if constant <= MAX_CONSTANT => {
+                        Ok(Some(Instruction::A(Value::Constant(constant))))
+                    }
+                    _ => Err(format!("Invalid constant: {}", value)),
+                }
+            } else {
+                check_symbol(value)?;
+                Ok(Some(Instruction::at(value.to_string())))
+            }
+        } else {
+            let (dest, rest) = match line.split_once('=') {
+                Some((dest, rest)) => (
+                    Some(Dest::parse(dest).ok_or(format!("Invalid dest: {}", dest))?),
+                    rest,
+                ),
+                None => (None, line.as_str()),
+            };
+            let (comp, jump) = match rest.split_once(';') {
+                Some((comp, jump)) => (
+                    comp,
+                    Some(Jump::parse(jump).ok_or(format!("Invalid jump: {}", jump))?),
+                ),
+                None => (rest, None),
+            };
+            let comp = Comp::parse(comp).ok_or(format!("Invalid comp: {}", comp))?;
+            Ok(Some(Instruction::C { dest, comp, jump }))
+        }
+    }
+
+    /// Decodes a machine word; labels and symbols are gone by then, so A-instructions hold
+    /// constants
+    pub fn decode(word: u16) -> Result<Instruction, String> {
+        if word & 0x8000 == 0 {
+            return Ok(Instruction::A(Value::Constant(word)));
+        }
+
+        let (comp, _, _) = COMPS
+            .iter()
+            .find(|(_, _, bits)| *bits == (word >> 6) & 0x7F)
+            .ok_or(format!("Invalid comp bits in {:016b}", word))?;
+        let dest = DESTS
+            .iter()
+            .map(|(dest, _)| *dest)
+            .find(|dest| dest.bits() == (word >> 3) & 0b111);
+        let jump = match word & 0b111 {
+            0 => None,
+            bits => Some(JUMPS[bits as usize - 1].0),
+        };
+        Ok(Instruction::C {
+            dest,
+            comp: *comp,
+            jump,
+        })
+    }
+}
+
+fn check_symbol(symbol: &str) -> Result<(), String> {
+    let valid = !symbol.is_empty()
+        && !symbol.starts_with(|c: char| c.is_ascii_digit())
+        && symbol
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.$:".contains(c));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid symbol: {}", symbol))
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Constant(constant) => write!(f, "{}", constant),
+            Value::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+impl fmt::Display for Dest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (_, name) = DESTS.iter().find(|(dest, _)| dest == self).unwrap();
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Comp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (_, name, _) = COMPS.iter().find(|(comp, _, _)| comp == self).unwrap();
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Jump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (_, name) = JUMPS.iter().find(|(jump, _)| jump == self).unwrap();
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::A(value) => write!(f, "@{}", value),
+            Instruction::Label(label) => write!(f, "({})", label),
+            Instruction::Comment(text) => write!(f, "// {}", text),
+            Instruction::C { dest, comp, jump } => {
+                if let Some(dest) = dest {
+                    write!(f, "{}=", dest)?;
+                }
+                write!(f, "{}", comp)?;
+                if let Some(jump) = jump {
+                    write!(f, ";{}", jump)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Prints a program one instruction per line
+pub fn print(program: &[Instruction]) -> String {
+    program
+        .iter()
+        .map(|instruction| instruction.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Parses a whole program, reporting the line of the first invalid instruction
+pub fn parse(assembly: &str) -> Result<Vec<Instruction>, String> {
+    let mut program = Vec::new();
+    for (number, line) in assembly.lines().enumerate() {
+        if let Some(instruction) =
+            Instruction::parse(line).map_err(|error| format!("{}: {}", number + 1, error))?
+        {
+            program.push(instruction);
+        }
+    }
+    Ok(program)
+}
+
+/// Resolves labels to ROM addresses and variables to RAM addresses from 16, then encodes
+/// each instruction as a 16-bit word
+pub fn encode(program: &[Instruction]) -> Result<Vec<u16>, String> {
+    encode_with(program, &MemoryMap::default())
+}
+
+/// Like `encode`, for a machine laid out by `memory_map`: THIS and THAT name its pointer
+/// registers, and variables are allocated from its statics
+pub fn encode_with(program: &[Instruction], memory_map: &MemoryMap) -> Result<Vec<u16>, String> {
+    let mut symbols: HashMap<&str, u16> = PREDEFINED_SYMBOLS.iter().copied().collect();
+    symbols.insert("THIS", memory_map.pointer);
+    symbols.insert("THAT", memory_map.pointer + 1);
+
+    let mut address = 0;
+    for instruction in program {
+        match instruction {
+            Instruction::Label(label) => {
+                if symbols.insert(label, address).is_some() {
+                    return Err(format!("Label already defined: {}", label));
+                }
+            }
+            Instruction::Comment(_) => {}
+            _ => address += 1,
+        }
+    }
+    if address as usize > ROM_SIZE {
+        return Err(format!(
+            "Program too large for ROM: {} instructions",
+            address
+        ));
+    }
+
+    let mut next_variable = *memory_map.statics.start();
+    let mut words = Vec::new();
+    for instruction in program {
+        match instruction {
+            Instruction::Label(_) | Instruction::Comment(_) => {}
+            Instruction::A(Value::Constant(constant)) if *constant > MAX_CONSTANT => {
+                return Err(format!("Invalid constant: {}", constant));
+            }
+            Instruction::A(Value::Constant(constant)) => words.push(*constant),
+            Instruction::A(Value::Symbol(symbol)) => {
+                let address = match symbols.get(symbol.as_ref()) {
+                    Some(address) => *address,
+                    None if memory_map.statics.contains(&next_variable) => {
+                        symbols.insert(symbol.as_ref(), next_variable);
+                        next_variable += 1;
+                        next_variable - 1
+                    }
+                    None => return Err(format!("No room for variable: {}", symbol)),
+                };
+                words.push(address);
+            }
+            Instruction::C { dest, comp, jump } => words.push(
+                0b111 << 13
+                    | comp.bits() << 6
+                    | dest.map_or(0, Dest::bits) << 3
+                    | jump.map_or(0, Jump::bits),
+            ),
+        }
+    }
+    Ok(words)
+}
+
+/// Assembles a program into `.hack` text, one word per line in binary
+pub fn assemble(assembly: &str) -> Result<String, String> {
+    assemble_with(assembly, &MemoryMap::default())
+}
+
+/// Like `assemble`, for a machine laid out by `memory_map`
+pub fn assemble_with(assembly: &str, memory_map: &MemoryMap) -> Result<String, String> {
+    let words = encode_with(&parse(assembly)?, memory_map)?;
+    Ok(words
+        .iter()
+        .map(|word| format!("{:016b}\n", word))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble_add() {
+        let assembly = "// Computes R0 = 2 + 3\n@2\nD=A\n@3\nD=D+A\n@0\nM=D\n";
+
+        assert_eq!(
+            assemble(assembly).unwrap(),
+            "0000000000000010\n1110110000010000\n0000000000000011\n\
+             1110000010010000\n0000000000000000\n1110001100001000\n"
+        );
+    }
+
+    #[test]
+    fn test_symbols() {
+        let assembly = "@i\nM=1\n(LOOP)\n@sum\nAM=M+1\n@LOOP\n0;JMP\n@KBD\n@i";
+
+        assert_eq!(
+            encode(&parse(assembly).unwrap()).unwrap(),
+            vec![16, 0xEFC8, 17, 0xFDE8, 2, 0xEA87, 24576, 16]
+        );
+    }
+
+    #[test]
+    fn test_relocated_memory() {
+        let memory_map = MemoryMap {
+            pointer: 8,
+            temp: 0x100,
+            statics: 0x200..=0x201,
+            stack: 0x300..=0x7FF,
+            ..Default::default()
+        };
+        let program = parse("@THIS\n@THAT\n@x\n@y\n@x").unwrap();
+        assert_eq!(
+            encode_with(&program, &memory_map).unwrap(),
+            vec![8, 9, 0x200, 0x201, 0x200]
+        );
+
+        let program = parse("@x\n@y\n@z").unwrap();
+        assert_eq!(
+            encode_with(&program, &memory_map).unwrap_err(),
+            "No room for variable: z"
+        );
+    }
+
+    #[test]
+    fn test_dest_order_and_commuted_comp() {
+        assert_eq!(
+            Instruction::parse("DM = A+D ; JNE").unwrap(),
+            Some(Instruction::C {
+                dest: Some(Dest::MD),
+                comp: Comp::DPlusA,
+                jump: Some(Jump::JNE),
+            })
+        );
+        assert_eq!(Comp::parse("M|D"), Some(Comp::DOrM));
+        assert_eq!(Dest::parse("MM"), None);
+    }
+
+    #[test]
+    fn test_invalid_instructions() {
+        assert_eq!(
+            parse("@1\nD=D*A").unwrap_err(),
+            "2: Invalid comp: D*A".to_string()
+        );
+        assert!(parse("@32768").is_err());
+        assert!(parse("(1LOOP)").is_err());
+        assert!(encode(&parse("(A)\n(A)").unwrap()).is_err());
+        assert!(encode(&[Instruction::constant(0x8000)]).is_err());
+    }
+}
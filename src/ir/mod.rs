@@ -0,0 +1,293 @@
+use crate::command::Command;
+use std::collections::HashMap;
+
+pub type BlockId = usize;
+
+/// A VM file grouped into functions and basic blocks
+#[derive(Debug, PartialEq, Eq)]
+pub struct Module {
+    pub functions: Vec<Function>,
+}
+
+/// A VM function; code before the first `function` command has no name
+#[derive(Debug, PartialEq, Eq)]
+pub struct Function {
+    pub name: Option<String>,
+    pub nlocals: u16,
+    pub blocks: Vec<BasicBlock>,
+}
+
+/// A straight-line run of commands, entered only at the top and left only at the bottom
+#[derive(Debug, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub labels: Vec<String>,
+    pub commands: Vec<Command>,
+    pub terminator: Terminator,
+}
+
+/// How control leaves a basic block, with label targets resolved to blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terminator {
+    /// Continue into the next block
+    Fallthrough(BlockId),
+    Goto(BlockId),
+    IfGoto {
+        taken: BlockId,
+        fallthrough: BlockId,
+    },
+    Return,
+    /// Run off the end of the function
+    End,
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match *self {
+            Terminator::Fallthrough(next) | Terminator::Goto(next) => vec![next],
+            Terminator::IfGoto { taken, fallthrough } => vec![taken, fallthrough],
+            Terminator::Return | Terminator::End => vec![],
+        }
+    }
+}
+
+impl Module {
+    /// Builds the control-flow graph from a file's parsed commands
+    pub fn from_commands(commands: Vec<Command>) -> Result<Self, String> {
+        let mut functions = Vec::new();
+        let mut current = (None, 0, Vec::new());
+
+        for command in commands {
+            if let Command::Function(name, nlocals) = command {
+                let (name_so_far, nlocals_so_far, body) =
+                    std::mem::replace(&mut current, (Some(name), nlocals, Vec::new()));
+                if name_so_far.is_some() || !body.is_empty() {
+                    functions.push(Function::from_commands(name_so_far, nlocals_so_far, body)?);
+                }
+            } else {
+                current.2.push(command);
+            }
+        }
+        let (name, nlocals, body) = current;
+        if name.is_some() || !body.is_empty() {
+            functions.push(Function::from_commands(name, nlocals, body)?);
+        }
+
+        Ok(Module { functions })
+    }
+
+    /// Lowers the graph back to a flat command stream for the CodeWriter. Commands come back
+    /// in their original order; only jumps may name a different label of the same block.
+    pub fn into_commands(self) -> Vec<Command> {
+        self.functions
+            .into_iter()
+            .flat_map(Function::into_commands)
+            .collect()
+    }
+}
+
+impl Function {
+    fn from_commands(
+        name: Option<String>,
+        nlocals: u16,
+        commands: Vec<Command>,
+    ) -> Result<Self, String> {
+        // split into blocks, remembering jump targets by label until all labels are known
+        let mut blocks = Vec::new();
+        let mut jumps: Vec<Option<Command>> = Vec::new();
+        let mut labels = Vec::new();
+        let mut body = Vec::new();
+
+        for command in commands {
+            match command {
+                Command::Label(label) => {
+                    if !body.is_empty() {
+                        blocks.push((std::mem::take(&mut labels), std::mem::take(&mut body)));
+                        jumps.push(None);
+                    }
+                    labels.push(label);
+                }
+                Command::Goto(_) | Command::IfGoto(_) | Command::Return => {
+                    blocks.push((std::mem::take(&mut labels), std::mem::take(&mut body)));
+                    jumps.push(Some(command));
+                }
+                _ => body.push(command),
+            }
+        }
+        if !labels.is_empty() || !body.is_empty() {
+            blocks.push((labels, body));
+            jumps.push(None);
+        }
+
+        let mut targets: HashMap<&str, BlockId> = HashMap::new();
+        for (id, (labels, _)) in blocks.iter().enumerate() {
+            for label in labels {
+                if targets.insert(label, id).is_some() {
+                    return Err(format!("label {} defined more than once", label));
+                }
+            }
+        }
+        let resolve = |label: &str| {
+            targets
+                .get(label)
+                .copied()
+                .ok_or_else(|| format!("undefined label: {}", label))
+        };
+
+        let count = blocks.len();
+        let next = |id: BlockId| {
+            if id + 1 < count {
+                Terminator::Fallthrough(id + 1)
+            } else {
+                Terminator::End
+            }
+        };
+        let terminators = jumps
+            .iter()
+            .enumerate()
+            .map(|(id, jump)| match jump {
+                Some(Command::Goto(label)) => Ok(Terminator::Goto(resolve(label)?)),
+                Some(Command::IfGoto(label)) => Ok(Terminator::IfGoto {
+                    taken: resolve(label)?,
+                    // an if-goto at the very end of a function falls off its end
+                    fallthrough: id + 1,
+                }),
+                Some(_) => Ok(Terminator::Return),
+                None => Ok(next(id)),
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let mut blocks: Vec<BasicBlock> = blocks
+            .into_iter()
+            .zip(terminators)
+            .map(|((labels, commands), terminator)| BasicBlock {
+                labels,
+                commands,
+                terminator,
+            })
+            .collect();
+        if let Some(Terminator::IfGoto { fallthrough, .. }) = blocks.last().map(|b| b.terminator) {
+            if fallthrough == count {
+                blocks.push(BasicBlock {
+                    labels: Vec::new(),
+                    commands: Vec::new(),
+                    terminator: Terminator::End,
+                });
+            }
+        }
+
+        Ok(Function {
+            name,
+            nlocals,
+            blocks,
+        })
+    }
+
+    /// The blocks that can transfer control into each block
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut predecessors = vec![Vec::new(); self.blocks.len()];
+        for (id, block) in self.blocks.iter().enumerate() {
+            for successor in block.terminator.successors() {
+                predecessors[successor].push(id);
+            }
+        }
+        predecessors
+    }
+
+    fn into_commands(self) -> Vec<Command> {
+        let label_of = |id: BlockId| self.blocks[id].labels[0].clone();
+
+        let mut commands = Vec::new();
+        if let Some(name) = &self.name {
+            commands.push(Command::Function(name.clone(), self.nlocals));
+        }
+        for block in &self.blocks {
+            commands.extend(block.labels.iter().cloned().map(Command::Label));
+            commands.extend(block.commands.iter().cloned());
+            match block.terminator {
+                Terminator::Goto(target) => commands.push(Command::Goto(label_of(target))),
+                Terminator::IfGoto { taken, .. } => commands.push(Command::IfGoto(label_of(taken))),
+                Terminator::Return => commands.push(Command::Return),
+                Terminator::Fallthrough(_) | Terminator::End => {}
+            }
+        }
+        commands
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn build(source: &str) -> Module {
+        let commands = Parser::new(source).map(|line| line.unwrap()).collect();
+        Module::from_commands(commands).unwrap()
+    }
+
+    #[test]
+    fn test_blocks_and_edges() {
+        let module = build(
+            "function Main.loop 1
+            label LOOP
+            push local 0
+            if-goto DONE
+            goto LOOP
+            label DONE
+            push constant 0
+            return",
+        );
+
+        let function = &module.functions[0];
+        assert_eq!(function.name.as_deref(), Some("Main.loop"));
+        assert_eq!(function.blocks.len(), 3);
+        assert_eq!(
+            function.blocks[0].terminator,
+            Terminator::IfGoto {
+                taken: 2,
+                fallthrough: 1
+            }
+        );
+        assert_eq!(function.blocks[1].terminator, Terminator::Goto(0));
+        assert_eq!(function.blocks[2].terminator, Terminator::Return);
+        assert_eq!(function.predecessors(), vec![vec![1], vec![0], vec![0]]);
+    }
+
+    #[test]
+    fn test_top_level_code() {
+        let module = build("push constant 1\npush constant 2\nadd");
+
+        assert_eq!(module.functions.len(), 1);
+        assert_eq!(module.functions[0].name, None);
+        assert_eq!(module.functions[0].blocks[0].terminator, Terminator::End);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let source = "push constant 0
+            function Sys.init 0
+            call Main.main 0
+            label END
+            label HALT
+            goto END
+            function Main.main 2
+            push argument 0
+            if-goto SKIP
+            return
+            label SKIP
+            push constant 1
+            if-goto SKIP";
+        let commands: Vec<Command> = Parser::new(source).map(|line| line.unwrap()).collect();
+
+        assert_eq!(build(source).into_commands(), commands);
+    }
+
+    #[test]
+    fn test_undefined_label() {
+        let commands = vec![
+            Command::Function("f".to_string(), 0),
+            Command::Goto("NOWHERE".to_string()),
+        ];
+
+        assert!(Module::from_commands(commands).is_err());
+    }
+}
@@ -1,5 +1,11 @@
+pub mod backend;
 pub mod codewriter;
+pub mod hack;
+pub mod ir;
+pub mod optimizer;
 pub mod parser;
+pub mod sourcemap;
 pub mod translator;
+pub mod verifier;
 
 pub mod command;
@@ -1,30 +1,250 @@
 use std::env;
 use std::fs::{self, File};
-use std::io::{self, Read, Write};
+use std::io::{self, BufWriter, Read};
+use std::ops::RangeInclusive;
 use std::path::Path;
 
+use stack_vm::backend::c::CBackend;
+use stack_vm::backend::rust::RustBackend;
+use stack_vm::backend::x86::X86Backend;
+use stack_vm::backend::{Native, NativeTarget};
+use stack_vm::codewriter::{CodeWriter, CommentLevel};
+use stack_vm::hack::{self, MemoryMap};
+use stack_vm::sourcemap::SourceMap;
 use stack_vm::translator;
+use stack_vm::translator::Bootstrap;
 
-fn main() {
-    let (input_path, input_name, input_files) = get_input();
-    let output_filename = determine_output_path(&input_path, &input_name);
+/// Command-line flags, given as `--name` or `--name=value` alongside the input path
+struct Options {
+    comments: CommentLevel,
+    target: Target,
+    /// Also assemble Hack output into `.hack` machine code
+    assemble: bool,
+    /// Read a `.hack` file and write it back out as assembly, instead of translating
+    disassemble: bool,
+    /// Trap when SP leaves the stack
+    stack_checks: bool,
+    /// Trap when `this` or `that` point outside the heap and `heap_allowed`
+    heap_checks: bool,
+    heap_allowed: Vec<RangeInclusive<u16>>,
+    /// Count calls to each function in RAM
+    profile: bool,
+    /// By default, bootstrap only programs that define `Sys.init`
+    bootstrap: Bootstrap,
+    memory_map: MemoryMap,
+    /// Print how many instructions each function and kind of command takes
+    size_report: bool,
+    /// Inline calls to OS functions such as `Math.multiply`, unless the program defines them
+    intrinsics: bool,
+}
+
+/// What the VM program is translated to
+enum Target {
+    Hack,
+    C,
+    Rust,
+    X86,
+}
 
-    let mut output_file = File::create(&output_filename).expect("Failed to create output file");
+impl Target {
+    fn extension(&self) -> &str {
+        match self {
+            Target::Hack => "asm",
+            Target::C => "c",
+            Target::Rust => "rs",
+            Target::X86 => "s",
+        }
+    }
+}
 
-    let translated_code = translator::translate(input_files, true);
+fn main() {
+    let (path, options) = parse_args();
+    if options.disassemble {
+        disassemble(path.as_deref().expect("No .hack file given"));
+        return;
+    }
+    let (input_path, input_name, input_files) = get_input(path.as_deref());
+    let output_filename =
+        determine_output_path(&input_path, &input_name, options.target.extension());
 
-    writeln!(output_file, "{}", translated_code).expect("Failed to write to output file");
+    match options.target {
+        Target::Hack => {
+            let mut codewriter = CodeWriter::new();
+            codewriter.set_comment_level(options.comments);
+            codewriter.set_stack_checks(options.stack_checks);
+            codewriter.set_heap_checks(options.heap_checks);
+            for range in options.heap_allowed {
+                codewriter
+                    .allow_heap_access(range)
+                    .unwrap_or_else(|error| panic!("{}", error));
+            }
+            codewriter.set_profiling(options.profile);
+            codewriter.set_intrinsics(options.intrinsics);
+            codewriter
+                .set_memory_map(options.memory_map.clone())
+                .expect("Invalid memory map");
+            // the error lists where the code goes if the program outgrows the ROM
+            let source_map = write_output(&output_filename, |output_file| {
+                translator::translate_to_writer(
+                    &mut codewriter,
+                    input_files,
+                    options.bootstrap,
+                    output_file,
+                )
+            });
+            if options.size_report {
+                print!("{}", codewriter.size_report());
+            }
+
+            fs::write(format!("{}.map", output_filename), source_map.to_json())
+                .expect("Failed to write source map");
+            if options.assemble {
+                let translated_code =
+                    fs::read_to_string(&output_filename).expect("Failed to read output file");
+                let machine_code = hack::assemble_with(&translated_code, &options.memory_map)
+                    .expect("Assembly failed");
+                let hack_filename = Path::new(&output_filename).with_extension("hack");
+                fs::write(&hack_filename, machine_code).expect("Failed to write machine code");
+            }
+        }
+        Target::C => translate_native(CBackend::new(), options, input_files, &output_filename),
+        Target::Rust => {
+            translate_native(RustBackend::new(), options, input_files, &output_filename)
+        }
+        Target::X86 => translate_native(X86Backend::new(), options, input_files, &output_filename),
+    }
 
     println!("Translation complete: {}", output_filename);
 }
 
+/// Lays the native target's RAM out like the Hack target's and translates for it
+fn translate_native<T: NativeTarget>(
+    mut backend: Native<T>,
+    options: Options,
+    input_files: Vec<(String, String)>,
+    output_filename: &str,
+) {
+    backend
+        .set_memory_map(options.memory_map)
+        .expect("Invalid memory map");
+    write_output(output_filename, |output_file| {
+        translator::translate_to_writer(backend, input_files, options.bootstrap, output_file)
+    });
+}
+
+/// Streams the output into a temporary file beside `output_filename`, moved into place only
+/// once `translate` succeeds, so a failed translation leaves no partial output
+fn write_output<T>(
+    output_filename: &str,
+    translate: impl FnOnce(BufWriter<File>) -> Result<T, String>,
+) -> T {
+    let temporary = format!("{}.tmp", output_filename);
+    let output_file =
+        BufWriter::new(File::create(&temporary).expect("Failed to create output file"));
+    match translate(output_file) {
+        Ok(result) => {
+            fs::rename(&temporary, output_filename).expect("Failed to write output file");
+            result
+        }
+        Err(error) => {
+            let _ = fs::remove_file(&temporary);
+            panic!("Translation failed: {}", error);
+        }
+    }
+}
+
+/// Writes `{name}.dis.asm` next to `{name}.hack`, annotated from `{name}.asm.map` if the
+/// translator left one there
+fn disassemble(path: &str) {
+    let path = Path::new(path);
+    let machine_code = fs::read_to_string(path).expect("Failed to read input file");
+    let source_map = fs::read_to_string(path.with_extension("asm.map"))
+        .ok()
+        .map(|json| SourceMap::from_json(&json).expect("Invalid source map"));
+
+    let assembly =
+        hack::disassemble(&machine_code, source_map.as_ref()).expect("Disassembly failed");
+    let output_filename = path.with_extension("dis.asm");
+    fs::write(&output_filename, assembly).expect("Failed to write to output file");
+
+    println!("Disassembly complete: {}", output_filename.display());
+}
+
+/// Splits the arguments into the optional input path and the flags
+fn parse_args() -> (Option<String>, Options) {
+    let mut path = None;
+    let mut options = Options {
+        comments: CommentLevel::Command,
+        target: Target::Hack,
+        assemble: false,
+        disassemble: false,
+        stack_checks: false,
+        heap_checks: false,
+        heap_allowed: Vec::new(),
+        profile: false,
+        bootstrap: Bootstrap::Auto,
+        memory_map: MemoryMap::default(),
+        size_report: false,
+        intrinsics: true,
+    };
+
+    for arg in env::args().skip(1) {
+        match arg.split_once('=').unwrap_or((&arg, "")) {
+            ("--comments", "none") => options.comments = CommentLevel::None,
+            ("--comments", "command") => options.comments = CommentLevel::Command,
+            ("--comments", "verbose") => options.comments = CommentLevel::Verbose,
+            ("--target", "hack") => options.target = Target::Hack,
+            ("--target", "c") => options.target = Target::C,
+            ("--target", "rust") => options.target = Target::Rust,
+            ("--target", "x86") => options.target = Target::X86,
+            ("--assemble", "") => options.assemble = true,
+            ("--disassemble", "") => options.disassemble = true,
+            ("--stack-checks", "") => options.stack_checks = true,
+            ("--heap-checks", "") => options.heap_checks = true,
+            ("--heap-allow", range) => options.heap_allowed.push(parse_range(range)),
+            ("--profile", "") => options.profile = true,
+            ("--bootstrap", "") => options.bootstrap = Bootstrap::Always,
+            ("--no-bootstrap", "") => options.bootstrap = Bootstrap::Never,
+            ("--size-report", "") => options.size_report = true,
+            ("--no-intrinsics", "") => options.intrinsics = false,
+            ("--temp", address) => options.memory_map.temp = parse_address(address),
+            ("--pointer", address) => options.memory_map.pointer = parse_address(address),
+            ("--statics", range) => options.memory_map.statics = parse_range(range),
+            ("--stack", range) => options.memory_map.stack = parse_range(range),
+            ("--heap", range) => options.memory_map.heap = parse_range(range),
+            ("--traps", address) => options.memory_map.traps = parse_address(address),
+            ("--counters", range) => options.memory_map.counters = parse_range(range),
+            (flag, _) if flag.starts_with("--") => panic!("Invalid option: {}", arg),
+            _ => path = Some(arg),
+        }
+    }
+
+    (path, options)
+}
+
+fn parse_address(address: &str) -> u16 {
+    address
+        .parse()
+        .unwrap_or_else(|_| panic!("Invalid address: {}", address))
+}
+
+/// Parses an inclusive address range written `first-last`
+fn parse_range(range: &str) -> RangeInclusive<u16> {
+    let parse = |address: &str| address.parse::<u16>().ok();
+    match range.split_once('-') {
+        Some((first, last)) => match (parse(first), parse(last)) {
+            (Some(first), Some(last)) if first <= last => first..=last,
+            _ => panic!("Invalid address range: {}", range),
+        },
+        None => panic!("Invalid address range: {}", range),
+    }
+}
+
 /// Determines the input source (file, directory, or stdin) and returns:
 /// - `input_name`: Used for naming the output file.
 /// - `input_files`: A Vec of (filename, file contents) pairs.
-fn get_input() -> (String, String, Vec<(String, String)>) {
-    let args: Vec<String> = env::args().collect();
-
-    match args.get(1) {
+fn get_input(path: Option<&str>) -> (String, String, Vec<(String, String)>) {
+    match path {
         Some(path) => {
             let path = Path::new(path);
             if path.is_dir() {
@@ -44,22 +264,12 @@ fn get_input() -> (String, String, Vec<(String, String)>) {
     }
 }
 
-/// Reads all `.vm` files from a directory and returns their contents.
+/// Reads all `.vm` files under a directory, including its subdirectories, naming each by its
+/// path relative to the directory so same-named files in different subdirectories stay apart
 fn get_directory_input(path: &Path) -> (String, Vec<(String, String)>) {
-    let files: Vec<(String, String)> = fs::read_dir(path)
-        .expect("Failed to read directory")
-        .filter_map(|entry| {
-            let entry = entry.ok()?;
-            let file_path = entry.path();
-            if file_path.extension()?.to_str()? == "vm" {
-                let content = fs::read_to_string(&file_path).ok()?;
-                let filename = file_path.file_name()?.to_str()?.to_string();
-                Some((filename, content))
-            } else {
-                None
-            }
-        })
-        .collect();
+    let mut files = Vec::new();
+    read_vm_files(path, path, &mut files);
+    files.sort();
 
     if files.is_empty() {
         panic!("No .vm files found in directory.");
@@ -69,6 +279,22 @@ fn get_directory_input(path: &Path) -> (String, Vec<(String, String)>) {
     (directory_name, files)
 }
 
+fn read_vm_files(root: &Path, directory: &Path, files: &mut Vec<(String, String)>) {
+    for entry in fs::read_dir(directory).expect("Failed to read directory") {
+        let file_path = entry.expect("Failed to read directory").path();
+        if file_path.is_dir() {
+            read_vm_files(root, &file_path, files);
+        } else if file_path
+            .extension()
+            .is_some_and(|extension| extension == "vm")
+        {
+            let content = fs::read_to_string(&file_path).expect("Failed to read input file");
+            let filename = file_path.strip_prefix(root).unwrap().to_string_lossy();
+            files.push((filename.replace('\\', "/"), content));
+        }
+    }
+}
+
 /// Reads a single `.vm` file and returns its contents.
 fn get_file_input(path: &Path) -> (String, Vec<(String, String)>) {
     let content = fs::read_to_string(path).expect("Failed to read input file");
@@ -87,9 +313,9 @@ fn get_stdin_input() -> (String, Vec<(String, String)>) {
 }
 
 /// Determines the correct output file path based on input.
-fn determine_output_path(input_path: &str, input_name: &str) -> String {
+fn determine_output_path(input_path: &str, input_name: &str, extension: &str) -> String {
     let input_path = Path::new(input_path);
-    let output_filename = format!("{}.asm", input_name);
+    let output_filename = format!("{}.{}", input_name, extension);
 
     if input_path.is_dir() {
         input_path
@@ -0,0 +1,220 @@
+use crate::command::Command;
+use std::collections::{HashMap, HashSet};
+
+/// Simplifies control flow within each function: threads jumps through labels that
+/// immediately jump again, drops gotos to the very next label, and removes unreachable code
+pub fn optimize(commands: Vec<Command>) -> Vec<Command> {
+    let tagged = commands.into_iter().map(|command| (command, ())).collect();
+    optimize_tagged(tagged)
+        .into_iter()
+        .map(|(command, _)| command)
+        .collect()
+}
+
+/// Like `optimize`, keeping each surviving command paired with its tag (e.g. its source line)
+pub fn optimize_tagged<T>(commands: Vec<(Command, T)>) -> Vec<(Command, T)> {
+    let mut result = Vec::with_capacity(commands.len());
+    let mut function = Vec::new();
+
+    for (command, tag) in commands {
+        if matches!(command, Command::Function(..)) && !function.is_empty() {
+            result.extend(optimize_function(std::mem::take(&mut function)));
+        }
+        function.push((command, tag));
+    }
+    result.extend(optimize_function(function));
+
+    result
+}
+
+/// Labels are scoped to their function, so each one is optimized on its own
+fn optimize_function<T>(mut commands: Vec<(Command, T)>) -> Vec<(Command, T)> {
+    loop {
+        let mut changed = thread_jumps(&mut commands);
+        changed |= remove_jumps_to_next(&mut commands);
+        changed |= remove_unreachable(&mut commands);
+        if !changed {
+            return commands;
+        }
+    }
+}
+
+/// Retargets jumps to a label whose first command is another `goto`
+fn thread_jumps<T>(commands: &mut [(Command, T)]) -> bool {
+    // labels that immediately jump elsewhere
+    let mut forwards: HashMap<String, String> = HashMap::new();
+    for (i, (command, _)) in commands.iter().enumerate() {
+        if let Command::Label(label) = command {
+            if let Some((Command::Goto(target), _)) = commands[i..]
+                .iter()
+                .find(|(command, _)| !matches!(command, Command::Label(_)))
+            {
+                forwards.insert(label.clone(), target.clone());
+            }
+        }
+    }
+
+    let mut changed = false;
+    for (command, _) in commands.iter_mut() {
+        if let Command::Goto(label) | Command::IfGoto(label) = command {
+            let target = resolve(&forwards, label);
+            if target != *label {
+                *label = target;
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+/// Follows a chain of forwarding labels to its end, stopping if it loops
+fn resolve(forwards: &HashMap<String, String>, label: &str) -> String {
+    let mut seen = HashSet::new();
+    let mut current = label;
+    while let Some(next) = forwards.get(current) {
+        if !seen.insert(current) || seen.contains(next.as_str()) {
+            break;
+        }
+        current = next;
+    }
+    current.to_string()
+}
+
+/// Removes a `goto` whose target is one of the labels directly following it
+fn remove_jumps_to_next<T>(commands: &mut Vec<(Command, T)>) -> bool {
+    let before = commands.len();
+    // only labels are looked past, so removing one `goto` never changes whether another is kept
+    let keep: Vec<bool> = (0..commands.len())
+        .map(|i| match &commands[i].0 {
+            Command::Goto(target) => !commands[i + 1..]
+                .iter()
+                .take_while(|(command, _)| matches!(command, Command::Label(_)))
+                .any(|(command, _)| matches!(command, Command::Label(label) if label == target)),
+            _ => true,
+        })
+        .collect();
+    let mut keep = keep.into_iter();
+    commands.retain(|_| keep.next().unwrap_or(true));
+    commands.len() != before
+}
+
+/// Removes commands following an unconditional `goto` or `return`, up to the next label
+fn remove_unreachable<T>(commands: &mut Vec<(Command, T)>) -> bool {
+    let before = commands.len();
+    let mut reachable = true;
+    commands.retain(|(command, _)| {
+        match command {
+            Command::Label(_) | Command::Function(..) => reachable = true,
+            _ if !reachable => return false,
+            Command::Goto(_) | Command::Return => reachable = false,
+            _ => {}
+        }
+        true
+    });
+    commands.len() != before
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::MemorySegment;
+
+    fn label(name: &str) -> Command {
+        Command::Label(name.to_string())
+    }
+
+    fn goto(name: &str) -> Command {
+        Command::Goto(name.to_string())
+    }
+
+    #[test]
+    fn test_thread_jump_chain() {
+        let commands = vec![
+            Command::IfGoto("A".to_string()),
+            goto("A"),
+            label("B"),
+            Command::Add,
+            label("A"),
+            goto("C"),
+            label("C"),
+            goto("B"),
+        ];
+        let optimized = optimize(commands);
+
+        assert_eq!(
+            optimized,
+            vec![
+                Command::IfGoto("B".to_string()),
+                label("B"),
+                Command::Add,
+                label("A"),
+                goto("B"),
+                label("C"),
+                goto("B"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remove_goto_to_next_label() {
+        let commands = vec![Command::Add, goto("NEXT"), label("OTHER"), label("NEXT")];
+        let optimized = optimize(commands);
+
+        assert_eq!(optimized, vec![Command::Add, label("OTHER"), label("NEXT")]);
+    }
+
+    #[test]
+    fn test_remove_unreachable() {
+        let commands = vec![
+            Command::Return,
+            Command::Push(MemorySegment::Constant, 1),
+            goto("END"),
+            label("END"),
+            Command::Neg,
+            Command::Function("Other".to_string(), 0),
+            Command::Not,
+        ];
+        let optimized = optimize(commands);
+
+        assert_eq!(
+            optimized,
+            vec![
+                Command::Return,
+                label("END"),
+                Command::Neg,
+                Command::Function("Other".to_string(), 0),
+                Command::Not,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_halt_loop_preserved() {
+        let commands = vec![goto("END"), Command::Add, label("END"), goto("END")];
+        let optimized = optimize(commands);
+
+        assert_eq!(optimized, vec![label("END"), goto("END")]);
+    }
+
+    #[test]
+    fn test_tags_follow_commands() {
+        let commands = vec![(goto("END"), 1), (Command::Add, 2), (label("END"), 3)];
+        let optimized = optimize_tagged(commands);
+
+        assert_eq!(optimized, vec![(label("END"), 3)]);
+    }
+
+    #[test]
+    fn test_labels_scoped_per_function() {
+        let commands = vec![
+            Command::Function("f".to_string(), 0),
+            goto("L"),
+            Command::Function("g".to_string(), 0),
+            label("L"),
+            goto("M"),
+        ];
+        let optimized = optimize(commands.clone());
+
+        assert_eq!(optimized, commands);
+    }
+}
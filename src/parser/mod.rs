@@ -2,6 +2,7 @@ use crate::command::{Command, MemorySegment};
 
 pub struct Parser<'a> {
     lines: std::str::Lines<'a>,
+    line_number: usize,
     in_multiline_comment: bool,
 }
 
@@ -9,10 +10,16 @@ impl<'a> Parser<'a> {
     pub fn new(input: &'a str) -> Self {
         Parser {
             lines: input.lines(),
+            line_number: 0,
             in_multiline_comment: false,
         }
     }
 
+    /// The 1-based source line of the most recently parsed command
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
     /// Removes comments and whitespace
     fn clean(&mut self, line: &str) -> String {
         let trimmed = line.trim();
@@ -209,6 +216,7 @@ impl<'a> Iterator for Parser<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         while let Some(line) = self.lines.next() {
+            self.line_number += 1;
             let cleaned = self.clean(line);
             if !cleaned.is_empty() {
                 return Some(parse(&cleaned));
@@ -308,6 +316,16 @@ mod clean_tests {
 
         assert_eq!(commands.len(), 3);
     }
+
+    #[test]
+    fn test_parser_line_numbers() {
+        let mut parser = Parser::new("// header\n\npush constant 1\n/* a\n b */ add");
+
+        assert!(parser.next().is_some());
+        assert_eq!(parser.line_number(), 3);
+        assert!(parser.next().is_some());
+        assert_eq!(parser.line_number(), 5);
+    }
 }
 
 #[cfg(test)]
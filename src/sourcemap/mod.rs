@@ -0,0 +1,431 @@
+use std::fmt::{self, Write};
+use std::iter;
+use std::iter::Peekable;
+use std::rc::Rc;
+use std::str::Chars;
+
+/// The VM command(s) a run of assembly was generated from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+    /// Shared by all of a file's sources
+    pub file: Rc<str>,
+    pub line: usize,
+    pub command: String,
+}
+
+/// Links each line of generated assembly, and each ROM address once labels are
+/// resolved, back to the VM source that produced it
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    pub sources: Vec<Source>,
+    /// Index into `sources` for each asm line; `None` for generated code such as the bootstrap
+    pub asm_lines: Vec<Option<usize>>,
+    /// Index into `sources` for each ROM address
+    pub rom_addresses: Vec<Option<usize>>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap::default()
+    }
+
+    /// Records a block of assembly generated from `source`
+    pub fn add(&mut self, assembly: &str, source: Option<Source>) {
+        let instructions = assembly.lines().filter(|line| is_instruction(line));
+        self.add_lines(source, assembly.lines().count(), instructions.count());
+    }
+
+    /// Records `lines` lines of assembly generated from `source`, `instructions` of which
+    /// take up ROM
+    pub fn add_lines(&mut self, source: Option<Source>, lines: usize, instructions: usize) {
+        let index = source.map(|source| {
+            self.sources.push(source);
+            self.sources.len() - 1
+        });
+
+        self.asm_lines.extend(iter::repeat_n(index, lines));
+        self.rom_addresses
+            .extend(iter::repeat_n(index, instructions));
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\n  \"version\": 1,\n  \"sources\": [");
+        for (i, source) in self.sources.iter().enumerate() {
+            let separator = if i == 0 { "\n" } else { ",\n" };
+            write!(
+                json,
+                "{}    {{\"file\": {}, \"line\": {}, \"command\": {}}}",
+                separator,
+                quote(&source.file),
+                source.line,
+                quote(&source.command)
+            )
+            .unwrap();
+        }
+        write!(
+            json,
+            "\n  ],\n  \"asm\": {},\n  \"rom\": {}\n}}\n",
+            indices(&self.asm_lines),
+            indices(&self.rom_addresses)
+        )
+        .unwrap();
+        json
+    }
+
+    /// Reads back a map written by `to_json`
+    pub fn from_json(json: &str) -> Result<SourceMap, String> {
+        let mut chars = json.chars().peekable();
+        let map = parse_value(&mut chars)?;
+        skip_whitespace(&mut chars);
+        if chars.next().is_some() {
+            return Err("Trailing characters after source map".to_string());
+        }
+
+        let sources = map
+            .field("sources")?
+            .array()?
+            .iter()
+            .map(|source| {
+                Ok(Source {
+                    file: source.field("file")?.string()?.into(),
+                    line: source.field("line")?.number()?,
+                    command: source.field("command")?.string()?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        let indices = |name| -> Result<Vec<Option<usize>>, String> {
+            map.field(name)?
+                .array()?
+                .iter()
+                .map(|index| match index {
+                    Json::Null => Ok(None),
+                    index => index.number().map(Some),
+                })
+                .collect()
+        };
+
+        Ok(SourceMap {
+            sources,
+            asm_lines: indices("asm")?,
+            rom_addresses: indices("rom")?,
+        })
+    }
+}
+
+/// Just enough JSON for reading source maps back
+#[derive(Debug)]
+enum Json {
+    Null,
+    Number(usize),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn field(&self, name: &str) -> Result<&Json, String> {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value)
+                .ok_or(format!("Missing field: {}", name)),
+            _ => Err(format!("Expected an object with field: {}", name)),
+        }
+    }
+
+    fn array(&self) -> Result<&[Json], String> {
+        match self {
+            Json::Array(items) => Ok(items),
+            other => Err(format!("Expected an array, found {:?}", other)),
+        }
+    }
+
+    fn string(&self) -> Result<String, String> {
+        match self {
+            Json::String(value) => Ok(value.clone()),
+            other => Err(format!("Expected a string, found {:?}", other)),
+        }
+    }
+
+    fn number(&self) -> Result<usize, String> {
+        match self {
+            Json::Number(value) => Ok(*value),
+            other => Err(format!("Expected a number, found {:?}", other)),
+        }
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+fn expect(chars: &mut Peekable<Chars>, expected: char) -> Result<(), String> {
+    skip_whitespace(chars);
+    match chars.next() {
+        Some(c) if c == expected => Ok(()),
+        other => Err(format!("Expected '{}', found {:?}", expected, other)),
+    }
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Result<Json, String> {
+    skip_whitespace(chars);
+    match chars.peek() {
+        Some('n') => {
+            for c in "null".chars() {
+                expect(chars, c)?;
+            }
+            Ok(Json::Null)
+        }
+        Some('"') => parse_string(chars).map(Json::String),
+        Some('[') => {
+            let items = parse_sequence(chars, '[', ']', parse_value)?;
+            Ok(Json::Array(items))
+        }
+        Some('{') => {
+            let fields = parse_sequence(chars, '{', '}', |chars| {
+                skip_whitespace(chars);
+                let key = parse_string(chars)?;
+                expect(chars, ':')?;
+                Ok((key, parse_value(chars)?))
+            })?;
+            Ok(Json::Object(fields))
+        }
+        Some(c) if c.is_ascii_digit() => {
+            let mut digits = String::new();
+            while let Some(digit) = chars.next_if(|c| c.is_ascii_digit()) {
+                digits.push(digit);
+            }
+            digits
+                .parse()
+                .map(Json::Number)
+                .map_err(|error| error.to_string())
+        }
+        other => Err(format!("Unexpected {:?}", other)),
+    }
+}
+
+/// Parses `open item, item, ... close`
+fn parse_sequence<T>(
+    chars: &mut Peekable<Chars>,
+    open: char,
+    close: char,
+    mut item: impl FnMut(&mut Peekable<Chars>) -> Result<T, String>,
+) -> Result<Vec<T>, String> {
+    expect(chars, open)?;
+    let mut items = Vec::new();
+    skip_whitespace(chars);
+    if chars.next_if_eq(&close).is_some() {
+        return Ok(items);
+    }
+    loop {
+        items.push(item(chars)?);
+        skip_whitespace(chars);
+        match chars.next() {
+            Some(',') => {}
+            Some(c) if c == close => return Ok(items),
+            other => return Err(format!("Expected ',' or '{}', found {:?}", close, other)),
+        }
+    }
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    expect(chars, '"')?;
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(value),
+            Some('\\') => match chars.next() {
+                Some('u') => {
+                    let code: String = chars.by_ref().take(4).collect();
+                    let code = u32::from_str_radix(&code, 16).map_err(|error| error.to_string())?;
+                    value.push(char::from_u32(code).ok_or("Invalid escape")?);
+                }
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some(c) => value.push(c),
+                None => return Err("Unterminated string".to_string()),
+            },
+            Some(c) => value.push(c),
+            None => return Err("Unterminated string".to_string()),
+        }
+    }
+}
+
+/// Passes output through to another writer, counting its lines so that code can be mapped
+/// as it streams out rather than once it has all been kept
+#[derive(Debug)]
+pub struct Counter<W> {
+    out: W,
+    lines: usize,
+    instructions: usize,
+    line: Line,
+}
+
+/// What the line being written turned out to be, going by its first non-blank character
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Line {
+    Blank,
+    Instruction,
+    Other,
+}
+
+impl<W: fmt::Write> Counter<W> {
+    pub fn new(out: W) -> Self {
+        Counter {
+            out,
+            lines: 0,
+            instructions: 0,
+            line: Line::Blank,
+        }
+    }
+
+    /// Maps the lines finished since the last call to `source`
+    pub fn map(&mut self, source_map: &mut SourceMap, source: Option<Source>) {
+        source_map.add_lines(source, self.lines, self.instructions);
+        self.lines = 0;
+        self.instructions = 0;
+    }
+}
+
+impl<W: fmt::Write> fmt::Write for Counter<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            match (byte, self.line) {
+                (b'\n', line) => {
+                    self.lines += 1;
+                    self.instructions += (line == Line::Instruction) as usize;
+                    self.line = Line::Blank;
+                }
+                (b'/' | b'(', Line::Blank) => self.line = Line::Other,
+                (byte, Line::Blank) if !byte.is_ascii_whitespace() => self.line = Line::Instruction,
+                _ => {}
+            }
+        }
+        self.out.write_str(s)
+    }
+}
+
+/// Labels and comments don't occupy ROM
+fn is_instruction(line: &str) -> bool {
+    let line = line.trim();
+    !(line.is_empty() || line.starts_with("//") || line.starts_with('('))
+}
+
+fn indices(indices: &[Option<usize>]) -> String {
+    let items: Vec<String> = indices
+        .iter()
+        .map(|index| match index {
+            Some(index) => index.to_string(),
+            None => "null".to_string(),
+        })
+        .collect();
+    format!("[{}]", items.join(", "))
+}
+
+fn quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rom_addresses_skip_labels_and_comments() {
+        let mut map = SourceMap::new();
+        map.add("@256\nD=A", None);
+        map.add(
+            "// label LOOP\n(Main$LOOP)\n@Main$LOOP\n0;JMP",
+            Some(Source {
+                file: "Main.vm".into(),
+                line: 4,
+                command: "goto LOOP".to_string(),
+            }),
+        );
+
+        assert_eq!(
+            map.asm_lines,
+            vec![None, None, Some(0), Some(0), Some(0), Some(0)]
+        );
+        assert_eq!(map.rom_addresses, vec![None, None, Some(0), Some(0)]);
+    }
+
+    #[test]
+    fn test_counter_maps_streamed_lines() {
+        let mut output = String::new();
+        let mut map = SourceMap::new();
+        let mut counter = Counter::new(&mut output);
+        counter.write_str("@256\nD=").unwrap();
+        counter.write_str("A\n").unwrap();
+        counter.map(&mut map, None);
+        counter
+            .write_str("// goto LOOP\n(Main$LOOP)\n  @Main$LOOP\n0;JMP\n")
+            .unwrap();
+        counter.map(
+            &mut map,
+            Some(Source {
+                file: "Main.vm".into(),
+                line: 4,
+                command: "goto LOOP".to_string(),
+            }),
+        );
+
+        assert_eq!(
+            output,
+            "@256\nD=A\n// goto LOOP\n(Main$LOOP)\n  @Main$LOOP\n0;JMP\n"
+        );
+        assert_eq!(
+            map.asm_lines,
+            vec![None, None, Some(0), Some(0), Some(0), Some(0)]
+        );
+        assert_eq!(map.rom_addresses, vec![None, None, Some(0), Some(0)]);
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let mut map = SourceMap::new();
+        map.add("@256\nD=A", None);
+        map.add(
+            "// call\n@Main.f\n0;JMP",
+            Some(Source {
+                file: "lib/A \"quoted\"\n.vm".into(),
+                line: 12,
+                command: "call Main.f 0".to_string(),
+            }),
+        );
+
+        assert_eq!(SourceMap::from_json(&map.to_json()), Ok(map));
+        assert!(SourceMap::from_json("{\"version\": 1}").is_err());
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut map = SourceMap::new();
+        map.add(
+            "// push constant 1\n@1",
+            Some(Source {
+                file: "A \"quoted\".vm".into(),
+                line: 1,
+                command: "push constant 1".to_string(),
+            }),
+        );
+
+        assert_eq!(
+            map.to_json(),
+            "{\n  \"version\": 1,\n  \"sources\": [\n    {\"file\": \"A \\\"quoted\\\".vm\", \
+             \"line\": 1, \"command\": \"push constant 1\"}\n  ],\n  \"asm\": [0, 0],\n  \
+             \"rom\": [0]\n}\n"
+        );
+    }
+}
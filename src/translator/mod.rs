@@ -1,26 +1,441 @@
+use crate::backend::Backend;
 use crate::codewriter::CodeWriter;
+use crate::command::Command;
+use crate::ir::Module;
+use crate::optimizer;
 use crate::parser::Parser;
+use crate::sourcemap::{Counter, Source, SourceMap};
+use std::collections::HashSet;
+use std::fmt::{self, Write};
+use std::io;
+use std::rc::Rc;
 
-pub fn translate(inputs: Vec<(String, String)>, do_bootstrap: bool) -> String {
+/// Whether the output starts with a bootstrap that sets up the machine and calls the entry
+/// function
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Bootstrap {
+    /// Only when the inputs define the backend's entry function
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl From<bool> for Bootstrap {
+    fn from(do_bootstrap: bool) -> Self {
+        if do_bootstrap {
+            Bootstrap::Always
+        } else {
+            Bootstrap::Never
+        }
+    }
+}
+
+pub fn translate(
+    inputs: Vec<(String, String)>,
+    bootstrap: impl Into<Bootstrap>,
+) -> Result<String, String> {
+    translate_with(CodeWriter::new(), inputs, bootstrap).map(|(result, _)| result)
+}
+
+/// Translates with a configured backend, also mapping the generated code back to VM source
+/// lines
+pub fn translate_with<B: Backend>(
+    backend: B,
+    inputs: Vec<(String, String)>,
+    bootstrap: impl Into<Bootstrap>,
+) -> Result<(String, SourceMap), String> {
     let mut result = String::new();
-    let mut codewriter = CodeWriter::new();
+    let source_map = translate_into(backend, inputs, bootstrap, &mut result)?;
+    Ok((result, source_map))
+}
+
+/// Like `translate_into`, for sinks such as files that take bytes
+pub fn translate_to_writer<B: Backend, W: io::Write>(
+    backend: B,
+    inputs: Vec<(String, String)>,
+    bootstrap: impl Into<Bootstrap>,
+    out: W,
+) -> Result<SourceMap, String> {
+    let mut writer = IoWriter { out, error: None };
+    let result = translate_into(backend, inputs, bootstrap, &mut writer)
+        .and_then(|source_map| writer.out.flush().map_err(write_error).map(|_| source_map));
+    match writer.error {
+        Some(error) => Err(write_error(error)),
+        None => result,
+    }
+}
+
+/// Writes the generated code into `out` block by block, so the output as a whole is never
+/// kept in memory
+pub fn translate_into<B: Backend, W: fmt::Write>(
+    mut backend: B,
+    inputs: Vec<(String, String)>,
+    bootstrap: impl Into<Bootstrap>,
+    out: &mut W,
+) -> Result<SourceMap, String> {
+    let mut out = Counter::new(out);
+    let mut source_map = SourceMap::new();
+    let output_failed = |_| "Failed to write output".to_string();
+
+    let filenames: Vec<&str> = inputs
+        .iter()
+        .map(|(filename, _)| filename.as_str())
+        .collect();
+    let modules = module_names(&filenames)?;
+
+    // every file is parsed up front, so the bootstrap can depend on what they define
+    let mut files = Vec::new();
+    for ((filename, content), module) in inputs.into_iter().zip(modules) {
+        let (commands, lines) = parse_file(&filename, &content)?;
+        files.push((filename, module, commands, lines));
+    }
+
+    let defined: HashSet<String> = files
+        .iter()
+        .flat_map(|(_, _, commands, _)| commands)
+        .filter_map(|command| match command {
+            Command::Function(name, _) => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+    backend.set_defined_functions(&defined);
+
+    let do_bootstrap = match bootstrap.into() {
+        Bootstrap::Always => true,
+        Bootstrap::Never => false,
+        Bootstrap::Auto => defined.contains(backend.entry_point()),
+    };
+
+    backend.write_prologue(&mut out).map_err(output_failed)?;
+    out.map(&mut source_map, None);
 
     if do_bootstrap {
-        result.push_str(&codewriter.write_bootstrap());
-        result.push('\n');
+        backend.write_bootstrap(&mut out).map_err(output_failed)?;
+        out.map(&mut source_map, None);
+    }
+
+    for (filename, module, commands, lines) in files {
+        backend.set_file_context(module);
+        let file: Rc<str> = filename.into();
+
+        let mut position = 0;
+        while position < commands.len() {
+            backend.set_source_context(&file, lines[position]);
+            let consumed = backend
+                .write_next(&commands[position..], &mut out)
+                .map_err(output_failed)?;
+            out.map(
+                &mut source_map,
+                Some(Source {
+                    file: Rc::clone(&file),
+                    line: lines[position],
+                    command: describe(&commands[position..position + consumed]),
+                }),
+            );
+            position += consumed;
+        }
+    }
+
+    backend.write_epilogue(&mut out).map_err(output_failed)?;
+    out.map(&mut source_map, None);
+    backend.check()?;
+
+    Ok(source_map)
+}
+
+/// The commands a block of output came from, as the source map shows them
+fn describe(commands: &[Command]) -> String {
+    let mut description = String::new();
+    for command in commands {
+        if !description.is_empty() {
+            description.push_str("; ");
+        }
+        write!(description, "{}", command).unwrap();
+    }
+    description
+}
+
+/// Parses and optimizes a file's commands, alongside the line each came from
+fn parse_file(filename: &str, content: &str) -> Result<(Vec<Command>, Vec<usize>), String> {
+    let mut parser = Parser::new(content);
+    let mut commands = Vec::new();
+    let mut lines = Vec::new();
+    while let Some(line) = parser.next() {
+        let command =
+            line.map_err(|error| format!("{}:{}: {}", filename, parser.line_number(), error))?;
+        commands.push(command);
+        lines.push(parser.line_number());
+    }
+
+    // the graph lowers back to commands in their original order, so lines stay aligned
+    let module =
+        Module::from_commands(commands).map_err(|error| format!("{}: {}", filename, error))?;
+    let commands =
+        optimizer::optimize_tagged(module.into_commands().into_iter().zip(lines).collect());
+    Ok(commands.into_iter().unzip())
+}
+
+/// Lets `fmt::Write` output go to an `io::Write` sink, keeping the error behind a failed write
+struct IoWriter<W> {
+    out: W,
+    error: Option<io::Error>,
+}
+
+impl<W: io::Write> fmt::Write for IoWriter<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.out.write_all(s.as_bytes()).map_err(|error| {
+            self.error = Some(error);
+            fmt::Error
+        })
+    }
+}
+
+fn write_error(error: io::Error) -> String {
+    format!("Failed to write output: {}", error)
+}
+
+/// Names each file's module, which prefixes its statics: the file name without `.vm`, or,
+/// when files in different directories share a name, the whole path
+fn module_names(filenames: &[&str]) -> Result<Vec<String>, String> {
+    let stems: Vec<&str> = filenames.iter().map(|filename| stem(filename)).collect();
+    let names: Vec<String> = filenames
+        .iter()
+        .zip(&stems)
+        .map(|(filename, stem)| {
+            if stems.iter().filter(|other| *other == stem).count() > 1 {
+                path_module_name(filename)
+            } else {
+                stem.to_string()
+            }
+        })
+        .collect();
+
+    for (i, name) in names.iter().enumerate() {
+        if let Some(j) = names[..i].iter().position(|other| other == name) {
+            return Err(format!(
+                "{} and {} would share static variables as module {}",
+                filenames[j], filenames[i], name
+            ));
+        }
+    }
+    Ok(names)
+}
+
+fn stem(filename: &str) -> &str {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or(filename);
+    name.strip_suffix(".vm").unwrap_or(name)
+}
+
+/// Joins the path's components with `$`, replacing characters Hack symbols can't contain
+fn path_module_name(filename: &str) -> String {
+    let path = filename.strip_suffix(".vm").unwrap_or(filename);
+    path.split(['/', '\\'])
+        .filter(|component| !component.is_empty() && *component != ".")
+        .map(|component| {
+            component
+                .chars()
+                .map(|c| match c {
+                    c if c.is_ascii_alphanumeric() || "_.:".contains(c) => c,
+                    _ => '_',
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("$")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codewriter::BootstrapConfig;
+    use crate::hack::{self, Instruction};
+
+    fn input(filename: &str, content: &str) -> (String, String) {
+        (filename.to_string(), content.to_string())
+    }
+
+    #[test]
+    fn test_generated_labels_do_not_collide_with_user_functions() {
+        let inputs = vec![input(
+            "Main.vm",
+            "function $TRUE.1 0\npush constant 1\npush constant 2\neq\nreturn\n\
+             function $OUT.1 0\ncall $TRUE.1 0\nlabel ret.1\nreturn",
+        )];
+
+        assert!(translate(inputs, false).is_ok());
+    }
+
+    #[test]
+    fn test_undefined_call_target_fails() {
+        let inputs = vec![input("Main.vm", "function Main.main 0\ncall Missing.f 0")];
+
+        let error = translate(inputs, false).unwrap_err();
+        assert_eq!(error, "jump to undefined label: Missing.f");
+    }
+
+    #[test]
+    fn test_same_named_files_do_not_share_statics() {
+        let inputs = vec![
+            input(
+                "lib1/Utils.vm",
+                "function Utils.set 0\npush argument 0\npop static 0\nreturn",
+            ),
+            input(
+                "lib2/Utils.vm",
+                "function Utils.get 0\npush static 0\nreturn",
+            ),
+        ];
+
+        let result = translate(inputs, false).unwrap();
+        assert!(result.contains("@lib1$$Utils.0\n"));
+        assert!(result.contains("@lib2$$Utils.0\n"));
+        assert!(!result.contains("@Utils.0\n"));
+
+        let program = hack::parse(&result).unwrap();
+        let words = hack::encode(&program).unwrap();
+        let address_of = |symbol: &str| {
+            let index = program
+                .iter()
+                .filter(|instruction| instruction.is_code())
+                .position(|instruction| *instruction == Instruction::at(symbol.to_string()))
+                .unwrap();
+            words[index]
+        };
+        assert_ne!(address_of("lib1$$Utils.0"), address_of("lib2$$Utils.0"));
+    }
+
+    #[test]
+    fn test_top_level_code_after_another_files_function() {
+        let inputs = vec![
+            input(
+                "A.vm",
+                "function A.g 0\npush constant 1\nreturn\n\
+                 function A.f 0\ncall A.g 0\nreturn",
+            ),
+            input("B.vm", "call A.g 0\npop temp 0"),
+        ];
+
+        let result = translate(inputs, false).unwrap();
+        assert!(result.contains("(A.f$ret.1)\n"));
+        assert!(result.contains("(B$ret.1)\n"));
+    }
+
+    #[test]
+    fn test_defined_os_functions_called() {
+        let main = "function Main.main 0\npush constant 6\npush constant 7\n\
+                    call Math.multiply 2\nreturn";
+        let math = "function Math.multiply 0\npush argument 0\nreturn";
+
+        let inlined = translate(vec![input("Main.vm", main)], false).unwrap();
+        assert!(!inlined.contains("@Math.multiply\n"));
+
+        let inputs = vec![input("Main.vm", main), input("Math.vm", math)];
+        let called = translate(inputs, false).unwrap();
+        assert!(called.contains("@Math.multiply\n"));
+        assert!(!called.contains("$intrinsic.multiply"));
+    }
+
+    #[test]
+    fn test_ambiguous_modules_rejected() {
+        let inputs = vec![input("lib/Utils.vm", ""), input("lib/Utils.vm", "")];
+
+        let error = translate(inputs, false).unwrap_err();
+        assert_eq!(
+            error,
+            "lib/Utils.vm and lib/Utils.vm would share static variables as module lib$Utils"
+        );
     }
 
-    for (filename, content) in inputs {
-        let parser = Parser::new(&content);
-        codewriter.set_file_context(filename[0..filename.len() - 3].to_string());
+    /// Writes each command back out as VM code, one at a time
+    struct EchoBackend;
+
+    impl Backend for EchoBackend {
+        fn write_bootstrap(&mut self, out: &mut dyn fmt::Write) -> fmt::Result {
+            writeln!(out, "call Sys.init 0")
+        }
+
+        fn set_file_context(&mut self, _module: String) {}
+
+        fn write_next(
+            &mut self,
+            commands: &[Command],
+            out: &mut dyn fmt::Write,
+        ) -> Result<usize, fmt::Error> {
+            writeln!(out, "{}", commands[0])?;
+            Ok(1)
+        }
 
-        for line in parser {
-            let command = line.expect("Failed to parse command");
-            let asm_code = codewriter.write(&command);
-            result.push_str(&asm_code);
-            result.push('\n');
+        fn write_epilogue(&mut self, out: &mut dyn fmt::Write) -> fmt::Result {
+            writeln!(out, "// end")
         }
     }
 
-    result
+    #[test]
+    fn test_translate_with_other_backend() {
+        let inputs = vec![input("Main.vm", "push constant 1\npop temp 0")];
+
+        let (result, source_map) = translate_with(EchoBackend, inputs, true).unwrap();
+        assert_eq!(
+            result,
+            "call Sys.init 0\npush constant 1\npop temp 0\n// end\n"
+        );
+        assert_eq!(source_map.asm_lines, vec![None, Some(0), Some(1), None]);
+    }
+
+    #[test]
+    fn test_translate_to_writer_matches_translate() {
+        let source = "function Main.main 0\npush constant 7\nlabel END\ngoto END";
+        let mut output = Vec::new();
+
+        let source_map = translate_to_writer(
+            CodeWriter::new(),
+            vec![input("Main.vm", source)],
+            false,
+            &mut output,
+        )
+        .unwrap();
+        let (expected, expected_map) =
+            translate_with(CodeWriter::new(), vec![input("Main.vm", source)], false).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+        assert_eq!(source_map, expected_map);
+    }
+
+    #[test]
+    fn test_parse_error_location() {
+        let inputs = vec![input("Main.vm", "push constant 1\n\npush nowhere 2")];
+
+        let error = translate(inputs, false).unwrap_err();
+        assert!(error.starts_with("Main.vm:3: "));
+    }
+
+    #[test]
+    fn test_bootstrap_when_entry_defined() {
+        let sys = input("Sys.vm", "function Sys.init 0\nlabel END\ngoto END");
+        let main = input("Main.vm", "push constant 1\npop temp 0");
+
+        let (result, _) =
+            translate_with(EchoBackend, vec![main.clone(), sys], Bootstrap::Auto).unwrap();
+        assert!(result.starts_with("call Sys.init 0\n"));
+        let (result, _) = translate_with(EchoBackend, vec![main.clone()], Bootstrap::Auto).unwrap();
+        assert!(!result.contains("call Sys.init"));
+        let (result, _) = translate_with(EchoBackend, vec![main], Bootstrap::Always).unwrap();
+        assert!(result.starts_with("call Sys.init 0\n"));
+    }
+
+    #[test]
+    fn test_bootstrap_follows_configured_entry() {
+        let mut codewriter = CodeWriter::new();
+        codewriter.set_bootstrap(BootstrapConfig {
+            entry: "Main.main".to_string(),
+            ..Default::default()
+        });
+        let inputs = vec![input(
+            "Main.vm",
+            "function Main.main 0\nlabel END\ngoto END",
+        )];
+
+        let (result, _) = translate_with(codewriter, inputs, Bootstrap::Auto).unwrap();
+        assert!(result.starts_with("// bootstrap\n"));
+    }
 }
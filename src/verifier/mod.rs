@@ -0,0 +1,95 @@
+use crate::hack::{self, Instruction, Value, PREDEFINED_SYMBOLS};
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+/// Checks generated assembly for labels defined more than once, labels shadowing predefined
+/// symbols, and jumps to labels that are never defined
+pub fn verify(assembly: &str) -> Result<(), String> {
+    let mut verifier = Verifier::default();
+    for instruction in hack::parse(assembly)? {
+        verifier.check(&instruction);
+    }
+    verifier.finish()
+}
+
+/// Runs the checks of `verify` over a program as it is generated, one instruction at a time
+#[derive(Debug, Default)]
+pub struct Verifier {
+    errors: Vec<String>,
+    labels: HashSet<String>,
+    /// Symbols loaded right before a jump, i.e. that jump's target, in order
+    targets: Vec<Cow<'static, str>>,
+    loaded: Option<Cow<'static, str>>,
+}
+
+impl Verifier {
+    pub fn check(&mut self, instruction: &Instruction) {
+        match instruction {
+            Instruction::Label(label) => {
+                if PREDEFINED_SYMBOLS.iter().any(|(symbol, _)| symbol == label) {
+                    self.errors
+                        .push(format!("label shadows predefined symbol: {}", label));
+                }
+                if !self.labels.insert(label.clone()) {
+                    self.errors
+                        .push(format!("label defined more than once: {}", label));
+                }
+            }
+            Instruction::Comment(_) => {}
+            Instruction::A(Value::Symbol(symbol)) => self.loaded = Some(symbol.clone()),
+            Instruction::C { jump: Some(_), .. } => self.targets.extend(self.loaded.take()),
+            _ => self.loaded = None,
+        }
+    }
+
+    pub fn finish(mut self) -> Result<(), String> {
+        let mut undefined = HashSet::new();
+        for target in &self.targets {
+            if !self.labels.contains(target.as_ref()) && undefined.insert(target) {
+                self.errors
+                    .push(format!("jump to undefined label: {}", target));
+            }
+        }
+
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self.errors.join("\n"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_program() {
+        assert_eq!(
+            verify("(LOOP)\n@LOOP // again\n0;JMP\n@R15\nA=M\n0;JMP"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_duplicate_label() {
+        let error = verify("(Main.main)\n@0\n(Main.main)").unwrap_err();
+        assert_eq!(error, "label defined more than once: Main.main");
+    }
+
+    #[test]
+    fn test_shadowed_predefined_symbol() {
+        assert!(verify("(SP)").is_err());
+    }
+
+    #[test]
+    fn test_undefined_jump_target() {
+        let error = verify("@Sys.init\n0;JMP\n@Sys.init\n0;JMP").unwrap_err();
+        assert_eq!(error, "jump to undefined label: Sys.init");
+    }
+
+    #[test]
+    fn test_variables_are_not_jump_targets() {
+        assert_eq!(verify("@Main.0\nD=M\n@5\nD;JGT"), Ok(()));
+    }
+}
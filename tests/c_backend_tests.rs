@@ -0,0 +1,58 @@
+use stack_vm::backend::c::CBackend;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+mod common;
+use common::CourseTest;
+
+#[test]
+fn test_stack_arithmetic() {
+    for test in ["SimpleAdd", "StackTest"] {
+        assert!(run_native_test(&format!("StackArithmetic/{}", test), false));
+    }
+}
+
+#[test]
+fn test_memory_access() {
+    for test in ["BasicTest", "PointerTest", "StaticTest"] {
+        assert!(run_native_test(&format!("MemoryAccess/{}", test), false));
+    }
+}
+
+#[test]
+fn test_program_flow() {
+    for test in ["BasicLoop", "FibonacciSeries"] {
+        assert!(run_native_test(&format!("ProgramFlow/{}", test), false));
+    }
+}
+
+#[test]
+fn test_function_calls() {
+    assert!(run_native_test("FunctionCalls/SimpleFunction", false));
+    for test in ["NestedCall", "FibonacciElement", "StaticsTest"] {
+        assert!(run_native_test(&format!("FunctionCalls/{}", test), true));
+    }
+}
+
+fn run_native_test(test: &str, do_bootstrap: bool) -> bool {
+    common::run_native_test(test, CBackend::new(), do_bootstrap, compile)
+}
+
+/// Compiles the C output with cc
+fn compile(test: &CourseTest, code: String) -> PathBuf {
+    let build_dir = test.build_dir("c");
+    let source = build_dir.join(format!("{}.c", test.name));
+    let binary = build_dir.join(&test.name);
+    fs::write(&source, code).expect("Failed to write C output");
+
+    let status = Command::new("cc")
+        .args(["-std=c99", "-O1", "-o"])
+        .arg(&binary)
+        .arg(&source)
+        .status()
+        .expect("Failed to execute cc");
+    assert!(status.success(), "{} did not compile", test.name);
+
+    binary
+}
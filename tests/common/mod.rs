@@ -0,0 +1,150 @@
+//! Helpers for running the course tests against native backends, without the CPU emulator
+
+use stack_vm::backend::Backend;
+use stack_vm::translator;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A course test directory's VM files, its RAM settings, and the RAM it expects at the end
+pub struct CourseTest {
+    pub name: String,
+    pub vm_files: Vec<(String, String)>,
+    /// `set RAM[address] value` commands from the test script
+    pub settings: Vec<(u16, i32)>,
+    /// The addresses of every `output`, paired with the values in the comparison file
+    pub expected: Vec<(u16, i32)>,
+}
+
+impl CourseTest {
+    pub fn load(test: &str) -> Self {
+        let test_dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("test_data")
+            .join(test);
+        let name = test_dir.file_name().unwrap().to_string_lossy().to_string();
+
+        let script = fs::read_to_string(test_dir.join(format!("{}.tst", name)))
+            .expect("Failed to read test script");
+        let (settings, outputs) = parse_test_script(&script);
+
+        let comparison = fs::read_to_string(test_dir.join(format!("{}.cmp", name)))
+            .expect("Failed to read comparison file");
+        // header rows name the columns; every other row holds one output's values
+        let values = comparison
+            .lines()
+            .flat_map(|line| line.split('|'))
+            .filter_map(|value| value.trim().parse().ok());
+
+        CourseTest {
+            vm_files: gather_vm_files(&test_dir),
+            settings,
+            expected: outputs.into_iter().zip(values).collect(),
+            name,
+        }
+    }
+
+    /// The settings as `address=value` arguments for a compiled program
+    pub fn arguments(&self) -> Vec<String> {
+        self.settings
+            .iter()
+            .map(|(address, value)| format!("{}={}", address, value))
+            .collect()
+    }
+
+    /// A scratch directory for this test's build output
+    pub fn build_dir(&self, target: &str) -> PathBuf {
+        let build_dir = std::env::temp_dir().join(format!("stack_vm_{}_{}", target, self.name));
+        fs::create_dir_all(&build_dir).expect("Failed to create build directory");
+        build_dir
+    }
+}
+
+/// Translates the test's VM files with `backend`, builds the output with `compile`, runs the
+/// binary it returns with the RAM the test script sets, and compares the RAM it prints with
+/// the comparison file
+#[allow(dead_code)] // the x86 tests check an exit status instead
+pub fn run_native_test<B: Backend>(
+    test: &str,
+    backend: B,
+    do_bootstrap: bool,
+    compile: impl FnOnce(&CourseTest, String) -> PathBuf,
+) -> bool {
+    let test = CourseTest::load(test);
+
+    let (code, _) = translator::translate_with(backend, test.vm_files.clone(), do_bootstrap)
+        .expect("Failed to translate");
+    let binary = compile(&test, code);
+
+    let output = Command::new(&binary)
+        .args(test.arguments())
+        .args(test.expected.iter().map(|(address, _)| address.to_string()))
+        .output()
+        .expect("Failed to run compiled program");
+    let got: Vec<i32> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| line.parse().unwrap())
+        .collect();
+    let expected: Vec<i32> = test.expected.iter().map(|(_, value)| *value).collect();
+
+    if got != expected {
+        println!("{}: TARGET {:?}, GOT {:?}", test.name, expected, got);
+        return false;
+    }
+    true
+}
+
+fn parse_test_script(script: &str) -> (Vec<(u16, i32)>, Vec<u16>) {
+    let script: String = script
+        .lines()
+        .map(|line| line.split("//").next().unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut settings = Vec::new();
+    let mut output_list = Vec::new();
+    let mut outputs = Vec::new();
+
+    for statement in script.split([',', ';', '{', '}']) {
+        let words: Vec<&str> = statement.split_whitespace().collect();
+        match words.as_slice() {
+            ["set", target, value] => {
+                if let Some(address) = ram_address(target) {
+                    settings.push((address, value.parse().unwrap()));
+                }
+            }
+            ["output-list", columns @ ..] => {
+                output_list = columns
+                    .iter()
+                    .filter_map(|column| ram_address(column))
+                    .collect();
+            }
+            ["output"] => outputs.extend(&output_list),
+            _ => {}
+        }
+    }
+
+    (settings, outputs)
+}
+
+fn ram_address(word: &str) -> Option<u16> {
+    word.strip_prefix("RAM[")?.split(']').next()?.parse().ok()
+}
+
+fn gather_vm_files(dir_path: &Path) -> Vec<(String, String)> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir_path)
+        .expect("Failed to read directory")
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "vm"))
+        .collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let content = fs::read_to_string(&path).expect("Failed to read VM file");
+            (
+                path.file_name().unwrap().to_string_lossy().to_string(),
+                content,
+            )
+        })
+        .collect()
+}
@@ -100,7 +100,8 @@ fn run_cpu_emulator_test(test_dir: &str, do_bootstrap: bool) -> bool {
 
     let asm_output = PathBuf::from(test_dir).join(format!("{}.asm", test_name));
 
-    let translated_code = translator::translate(vm_files, do_bootstrap);
+    let translated_code =
+        translator::translate(vm_files, do_bootstrap).expect("Failed to translate");
     fs::write(&asm_output, translated_code).expect("Failed to write assembly output");
 
     let test_script = PathBuf::from(test_dir).join(format!("{}.tst", test_name));
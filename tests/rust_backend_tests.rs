@@ -0,0 +1,86 @@
+use stack_vm::backend::rust::RustBackend;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+mod common;
+use common::CourseTest;
+
+/// Sets RAM from `address=value` arguments, runs the included program, and prints
+/// `RAM[address]` for each bare `address` argument
+const HARNESS: &str = r#"
+include!("program.rs");
+
+fn main() {
+    let mut ram = [0i16; 32768];
+    let arguments: Vec<String> = std::env::args().skip(1).collect();
+    for argument in &arguments {
+        if let Some((address, value)) = argument.split_once('=') {
+            ram[address.parse::<usize>().unwrap()] = value.parse().unwrap();
+        }
+    }
+    run(&mut ram);
+    for argument in &arguments {
+        if !argument.contains('=') {
+            println!("{}", ram[argument.parse::<usize>().unwrap()]);
+        }
+    }
+}
+"#;
+
+#[test]
+fn test_stack_arithmetic() {
+    for test in ["SimpleAdd", "StackTest"] {
+        assert!(run_native_test(&format!("StackArithmetic/{}", test), false));
+    }
+}
+
+#[test]
+fn test_memory_access() {
+    for test in ["BasicTest", "PointerTest", "StaticTest"] {
+        assert!(run_native_test(&format!("MemoryAccess/{}", test), false));
+    }
+}
+
+#[test]
+fn test_program_flow() {
+    for test in ["BasicLoop", "FibonacciSeries"] {
+        assert!(run_native_test(&format!("ProgramFlow/{}", test), false));
+    }
+}
+
+#[test]
+fn test_function_calls() {
+    assert!(run_native_test("FunctionCalls/SimpleFunction", false));
+    for test in ["NestedCall", "FibonacciElement", "StaticsTest"] {
+        assert!(run_native_test(&format!("FunctionCalls/{}", test), true));
+    }
+}
+
+fn run_native_test(test: &str, do_bootstrap: bool) -> bool {
+    common::run_native_test(test, RustBackend::new(), do_bootstrap, compile)
+}
+
+/// Includes the Rust output in the harness and compiles it with rustc
+fn compile(test: &CourseTest, code: String) -> PathBuf {
+    let build_dir = test.build_dir("rust");
+    let source = build_dir.join("main.rs");
+    let binary = build_dir.join(&test.name);
+    fs::write(build_dir.join("program.rs"), code).expect("Failed to write Rust output");
+    fs::write(&source, HARNESS).expect("Failed to write harness");
+
+    let output = Command::new("rustc")
+        .args(["--edition", "2021", "-D", "warnings", "-o"])
+        .arg(&binary)
+        .arg(&source)
+        .output()
+        .expect("Failed to execute rustc");
+    assert!(
+        output.status.success(),
+        "{} did not compile:\n{}",
+        test.name,
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    binary
+}
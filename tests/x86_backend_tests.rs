@@ -0,0 +1,118 @@
+#![cfg(all(target_arch = "x86_64", target_os = "linux"))]
+
+use stack_vm::backend::x86::X86Backend;
+use stack_vm::translator;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+mod common;
+use common::CourseTest;
+
+#[test]
+fn test_stack_arithmetic() {
+    for test in ["SimpleAdd", "StackTest"] {
+        assert!(run_course_test(&format!("StackArithmetic/{}", test)));
+    }
+}
+
+#[test]
+fn test_memory_access() {
+    for test in ["BasicTest", "PointerTest", "StaticTest"] {
+        assert!(run_course_test(&format!("MemoryAccess/{}", test)));
+    }
+}
+
+#[test]
+fn test_program_flow() {
+    assert!(run_course_test("ProgramFlow/BasicLoop"));
+}
+
+#[test]
+fn test_recursive_calls() {
+    let sys = "function Sys.init 0\n\
+               push constant 256\npop pointer 1\n\
+               push constant 10\ncall Main.fibonacci 1\npop that 0\n\
+               label END\ngoto END";
+    let main = "function Main.fibonacci 0\n\
+                push argument 0\npush constant 2\nlt\nif-goto BASE\n\
+                push argument 0\npush constant 2\nsub\ncall Main.fibonacci 1\n\
+                push argument 0\npush constant 1\nsub\ncall Main.fibonacci 1\n\
+                add\nreturn\n\
+                label BASE\npush argument 0\nreturn";
+    let vm_files = vec![
+        ("Main.vm".to_string(), main.to_string()),
+        ("Sys.vm".to_string(), sys.to_string()),
+    ];
+
+    let build_dir = std::env::temp_dir().join("stack_vm_x86_Fibonacci");
+    fs::create_dir_all(&build_dir).expect("Failed to create build directory");
+    let binary = build(vm_files, true, &build_dir, "Fibonacci");
+
+    assert_eq!(run(&binary, &[]), 55);
+}
+
+/// Assembles the test's VM files and checks the exit status against the RAM[256] the
+/// comparison file expects
+fn run_course_test(test: &str) -> bool {
+    let test = CourseTest::load(test);
+    let (_, expected) = test
+        .expected
+        .iter()
+        .find(|(address, _)| *address == 256)
+        .expect("Test does not check RAM[256]");
+
+    let binary = build(
+        test.vm_files.clone(),
+        false,
+        &test.build_dir("x86"),
+        &test.name,
+    );
+    let status = run(&binary, &test.arguments());
+
+    if status != expected & 0xFF {
+        println!("{}: TARGET {}, GOT {}", test.name, expected & 0xFF, status);
+        return false;
+    }
+    true
+}
+
+fn build(
+    vm_files: Vec<(String, String)>,
+    do_bootstrap: bool,
+    build_dir: &Path,
+    name: &str,
+) -> PathBuf {
+    let (code, _) = translator::translate_with(X86Backend::new(), vm_files, do_bootstrap)
+        .expect("Failed to translate");
+    let source = build_dir.join(format!("{}.s", name));
+    let object = build_dir.join(format!("{}.o", name));
+    let binary = build_dir.join(name);
+    fs::write(&source, code).expect("Failed to write assembly output");
+
+    let status = Command::new("as")
+        .arg("-o")
+        .arg(&object)
+        .arg(&source)
+        .status()
+        .expect("Failed to execute as");
+    assert!(status.success(), "{} did not assemble", name);
+    let status = Command::new("ld")
+        .arg("-o")
+        .arg(&binary)
+        .arg(&object)
+        .status()
+        .expect("Failed to execute ld");
+    assert!(status.success(), "{} did not link", name);
+
+    binary
+}
+
+fn run(binary: &Path, arguments: &[String]) -> i32 {
+    Command::new(binary)
+        .args(arguments)
+        .status()
+        .expect("Failed to run program")
+        .code()
+        .expect("Program was killed")
+}